@@ -0,0 +1,936 @@
+use anyhow::Result;
+use ciborium::value::Value;
+use driftdb::{
+    types::{key_seq_pair::KeyAndSeq, SequenceNumber, SequenceValue},
+    ApplyResult, DeleteInstruction, Expirations, Key, MemoryBackend, PushInstruction, ReadRange,
+    StorageBackend, StorageError, ValueLog,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+const SNAPSHOT_FILE: &str = "snapshot.cbor";
+
+/// Number of write-ahead log records after which the room is checkpointed
+/// into a new snapshot and the log is started over. The room is only locked
+/// while it is copied; the snapshot is encoded and written in the background.
+const CHECKPOINT_RECORDS: usize = 10_000;
+
+/// When the write-ahead log is synced to disk.
+#[derive(Clone, Copy, Debug)]
+pub enum FsyncPolicy {
+    /// Sync after every record.
+    Always,
+
+    /// Sync after the given number of records.
+    Batch(usize),
+
+    /// Sync at most once per interval. Records written since the last sync are
+    /// synced by the next write or the next call to `flush`, whichever comes first.
+    Interval(Duration),
+}
+
+/// Point-in-time copy of a room, which the write-ahead log is replayed on top of.
+#[derive(Serialize, Deserialize, Default)]
+struct DiskSnapshot {
+    /// Generation of the write-ahead log which continues from this snapshot.
+    wal_generation: u64,
+
+    /// Every stored value, keyed by its `KeyAndSeq` string and in that order.
+    entries: Vec<(String, Arc<Value>)>,
+
+    /// Compaction marker of every key which has one.
+    #[serde(default)]
+    compacted: Vec<(Key, SequenceNumber)>,
+
+    /// The highest sequence number reserved by the room.
+    #[serde(default)]
+    reserved_sequence: SequenceNumber,
+
+    /// Default time-to-live of every key which has one.
+    #[serde(default)]
+    key_ttls: Vec<(Key, u64)>,
+
+    /// Expiry time of every stored value which has one.
+    #[serde(default)]
+    expirations: Vec<(Key, SequenceNumber, u64)>,
+}
+
+/// A record of the write-ahead log. Records are externally tagged, since
+/// untagged enums can not be decoded when a stored value contains a CBOR tag.
+#[derive(Serialize, Deserialize)]
+enum WalRecord<'a> {
+    /// The instructions of a single change to the room.
+    Apply(Cow<'a, ApplyResult>),
+
+    /// Sequence numbers up to and including this one may have been handed out.
+    Reserve { reserved_sequence: SequenceNumber },
+
+    /// The default time-to-live of a key was set or removed.
+    KeyTtl { ttl_key: Key, ttl: Option<u64> },
+}
+
+/// The contents of a room, which are served from memory.
+#[derive(Default)]
+struct Room {
+    memory: MemoryBackend,
+    reserved_sequence: SequenceNumber,
+    expirations: Expirations,
+}
+
+impl Room {
+    /// Apply a change which has been written to the write-ahead log.
+    fn apply(&mut self, result: &ApplyResult) {
+        result.apply_to(&mut self.memory);
+
+        let values = &mut self.expirations.values;
+        match &result.delete_instruction {
+            Some(DeleteInstruction::Delete) => {
+                values.remove(&result.key);
+            }
+            Some(DeleteInstruction::DeleteUpTo(seq)) => {
+                if let Some(expirations) = values.get_mut(&result.key) {
+                    expirations.retain(|s, _| s > seq);
+                }
+            }
+            None => {}
+        }
+
+        if let (Some(expires_at), Some(seq)) = (result.expires_at, result.pushed_seq()) {
+            values
+                .entry(result.key.clone())
+                .or_default()
+                .insert(seq, expires_at);
+        }
+    }
+
+    fn set_key_ttl(&mut self, key: &Key, ttl: Option<u64>) {
+        match ttl {
+            Some(ttl) => self.expirations.key_ttls.insert(key.clone(), ttl),
+            None => self.expirations.key_ttls.remove(key),
+        };
+    }
+
+    fn replay(&mut self, record: WalRecord<'_>) {
+        match record {
+            WalRecord::Apply(result) => self.apply(&result),
+            WalRecord::Reserve { reserved_sequence } => {
+                self.reserved_sequence = self.reserved_sequence.max(reserved_sequence);
+            }
+            WalRecord::KeyTtl { ttl_key, ttl } => self.set_key_ttl(&ttl_key, ttl),
+        }
+    }
+}
+
+fn wal_path(dir: &Path, generation: u64) -> PathBuf {
+    dir.join(format!("wal-{:020}.cbor", generation))
+}
+
+fn write_atomic(path: &Path, bytes: &[u8]) -> Result<()> {
+    let tmp_path = path.with_extension("tmp");
+
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)?;
+
+    // Make the rename itself durable.
+    File::open(
+        path.parent()
+            .expect("Snapshot should be in a room directory."),
+    )?
+    .sync_all()?;
+
+    Ok(())
+}
+
+/// Storage backend which serves reads from memory and persists a room in a
+/// directory on disk.
+///
+/// Every [`ApplyResult`] is appended to a write-ahead log as a single record
+/// before `apply` returns, so it is durable (subject to the [`FsyncPolicy`])
+/// before the change is broadcast. If the record can not be written, the
+/// change is rejected. Periodically, a new log is started and the room is
+/// written out as a snapshot in the background. When the room is opened, the
+/// logs since the latest snapshot are replayed on top of it. A record which was
+/// only partially written when the process died is discarded, but a record
+/// which can not be decoded otherwise stops the room from being opened.
+pub struct DiskBackend {
+    room: Room,
+    dir: PathBuf,
+    wal: File,
+
+    /// Length of the write-ahead log, up to the end of its last complete record.
+    wal_len: u64,
+
+    wal_generation: u64,
+    wal_records: usize,
+    fsync: FsyncPolicy,
+    unsynced_records: usize,
+    last_sync: Instant,
+
+    /// The snapshot being written in the background, if any.
+    checkpoint: Option<JoinHandle<Result<()>>>,
+}
+
+/// The state of a room at a checkpoint, copied while the room is locked.
+struct Checkpoint {
+    /// Generation of the write-ahead log which continues from this checkpoint.
+    wal_generation: u64,
+    streams: Vec<(Key, Vec<SequenceValue>)>,
+    compacted: Vec<(Key, SequenceNumber)>,
+    reserved_sequence: SequenceNumber,
+    key_ttls: Vec<(Key, u64)>,
+    expirations: Vec<(Key, SequenceNumber, u64)>,
+}
+
+impl Checkpoint {
+    /// Encode and write the snapshot, then remove the logs which it contains.
+    fn write(self, dir: &Path) -> Result<()> {
+        let mut entries: Vec<(String, Arc<Value>)> = self
+            .streams
+            .into_iter()
+            .flat_map(|(key, values)| {
+                values.into_iter().map(move |value| {
+                    (
+                        KeyAndSeq::new(key.clone(), value.seq).to_string(),
+                        value.value,
+                    )
+                })
+            })
+            .collect();
+        entries.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let snapshot = DiskSnapshot {
+            wal_generation: self.wal_generation,
+            entries,
+            compacted: self.compacted,
+            reserved_sequence: self.reserved_sequence,
+            key_ttls: self.key_ttls,
+            expirations: self.expirations,
+        };
+        let mut buffer = Vec::new();
+        ciborium::ser::into_writer(&snapshot, &mut buffer)?;
+
+        write_atomic(&dir.join(SNAPSHOT_FILE), &buffer)?;
+        remove_stale_logs(dir, self.wal_generation)
+    }
+}
+
+/// Replay the write-ahead log at `path` onto `room`, if it exists, returning
+/// the number of records it contains. A partially written record at its end is
+/// discarded; any other record which can not be decoded is an error.
+fn replay_log(path: &Path, room: &mut Room) -> Result<usize> {
+    if !path.exists() {
+        return Ok(0);
+    }
+
+    let bytes = std::fs::read(path)?;
+    let mut rest = bytes.as_slice();
+    let mut records = 0;
+
+    while !rest.is_empty() {
+        let remaining = rest.len();
+        let valid_len = bytes.len() - remaining;
+        match ciborium::de::from_reader::<WalRecord, _>(&mut rest) {
+            Ok(record) => {
+                room.replay(record);
+                records += 1;
+            }
+            Err(ciborium::de::Error::Io(err)) if err.kind() == ErrorKind::UnexpectedEof => {
+                tracing::warn!(
+                    ?path,
+                    valid_len,
+                    "Discarding incomplete write-ahead log record."
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(path)?
+                    .set_len(valid_len as u64)?;
+                break;
+            }
+            Err(err) => {
+                return Err(anyhow::anyhow!(
+                    "Failed to decode write-ahead log record at offset {} of {:?}: {:?}",
+                    valid_len,
+                    path,
+                    err
+                ));
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+impl DiskBackend {
+    /// Open the room stored in `dir`, creating it if it does not exist.
+    pub fn open(dir: &Path, fsync: FsyncPolicy) -> Result<Self> {
+        std::fs::create_dir_all(dir)?;
+
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        let snapshot: DiskSnapshot = if snapshot_path.exists() {
+            let bytes = std::fs::read(&snapshot_path)?;
+            ciborium::de::from_reader(bytes.as_slice())?
+        } else {
+            DiskSnapshot::default()
+        };
+
+        let mut subjects = HashMap::<Key, ValueLog>::new();
+        for (key, value) in snapshot.entries {
+            let key_and_seq = KeyAndSeq::from_str(&key).map_err(anyhow::Error::msg)?;
+            subjects
+                .entry(key_and_seq.key)
+                .or_default()
+                .insert(SequenceValue {
+                    value,
+                    seq: key_and_seq.seq,
+                });
+        }
+        for (key, seq) in snapshot.compacted {
+            subjects.entry(key).or_default().compacted_through = Some(seq);
+        }
+        let mut expirations = Expirations {
+            key_ttls: snapshot.key_ttls.into_iter().collect(),
+            ..Default::default()
+        };
+        for (key, seq, expires_at) in snapshot.expirations {
+            expirations
+                .values
+                .entry(key)
+                .or_default()
+                .insert(seq, expires_at);
+        }
+        let mut room = Room {
+            memory: MemoryBackend::new(subjects),
+            reserved_sequence: snapshot.reserved_sequence,
+            expirations,
+        };
+
+        // Logs after the snapshot's own remain if the process stopped before a
+        // checkpoint finished writing its snapshot.
+        let mut wal_generation = snapshot.wal_generation;
+        let mut wal_records = replay_log(&wal_path(dir, wal_generation), &mut room)?;
+        while wal_path(dir, wal_generation + 1).exists() {
+            wal_generation += 1;
+            wal_records = replay_log(&wal_path(dir, wal_generation), &mut room)?;
+        }
+
+        let path = wal_path(dir, wal_generation);
+        let wal = OpenOptions::new().create(true).append(true).open(&path)?;
+        let wal_len = wal.metadata()?.len();
+        remove_stale_logs(dir, snapshot.wal_generation)?;
+
+        Ok(Self {
+            room,
+            dir: dir.to_path_buf(),
+            wal,
+            wal_len,
+            wal_generation,
+            wal_records,
+            fsync,
+            unsynced_records: 0,
+            last_sync: Instant::now(),
+            checkpoint: None,
+        })
+    }
+
+    fn write<T: Serialize>(&mut self, record: &T) -> Result<()> {
+        let mut buffer = Vec::new();
+        ciborium::ser::into_writer(record, &mut buffer)?;
+
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Batch(size) => self.unsynced_records + 1 >= size,
+            FsyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+        };
+
+        let written =
+            self.wal.write_all(&buffer).and_then(
+                |()| {
+                    if sync {
+                        self.wal.sync_data()
+                    } else {
+                        Ok(())
+                    }
+                },
+            );
+        if let Err(err) = written {
+            // Remove whatever part of the record was written, so that records
+            // written after it are not discarded along with it on replay.
+            if let Err(err) = self.wal.set_len(self.wal_len) {
+                tracing::error!(?err, dir=?self.dir, "Failed to truncate write-ahead log.");
+            }
+            return Err(err.into());
+        }
+
+        self.wal_len += buffer.len() as u64;
+        self.wal_records += 1;
+        self.unsynced_records += 1;
+        if sync {
+            self.unsynced_records = 0;
+            self.last_sync = Instant::now();
+        }
+
+        Ok(())
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        if self.unsynced_records > 0 {
+            self.wal.sync_data()?;
+            self.unsynced_records = 0;
+        }
+        self.last_sync = Instant::now();
+
+        Ok(())
+    }
+
+    /// Copy the current state, and continue in a new write-ahead log. Values
+    /// are shared with the room rather than copied, so this is much cheaper
+    /// than encoding and writing the snapshot, which can be done without
+    /// holding up the room.
+    fn start_checkpoint(&mut self) -> Result<Checkpoint> {
+        // Records in the current log must stay durable until a snapshot
+        // containing them has been written.
+        self.sync()?;
+
+        let wal_generation = self.wal_generation + 1;
+        let wal = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(wal_path(&self.dir, wal_generation))?;
+
+        let mut streams = Vec::new();
+        let mut compacted = Vec::new();
+        for key in self.room.memory.keys() {
+            if let Some(seq) = self.room.memory.compacted_through(&key) {
+                compacted.push((key.clone(), seq));
+            }
+
+            let values = self.room.memory.get(&key, SequenceNumber::default());
+            streams.push((key, values));
+        }
+
+        let key_ttls = self
+            .room
+            .expirations
+            .key_ttls
+            .iter()
+            .map(|(key, ttl)| (key.clone(), *ttl))
+            .collect();
+        let expirations = self
+            .room
+            .expirations
+            .values
+            .iter()
+            .flat_map(|(key, values)| {
+                values
+                    .iter()
+                    .map(|(seq, expires_at)| (key.clone(), *seq, *expires_at))
+            })
+            .collect();
+
+        self.wal = wal;
+        self.wal_len = 0;
+        self.wal_generation = wal_generation;
+        self.wal_records = 0;
+        self.unsynced_records = 0;
+
+        Ok(Checkpoint {
+            wal_generation,
+            streams,
+            compacted,
+            reserved_sequence: self.room.reserved_sequence,
+            key_ttls,
+            expirations,
+        })
+    }
+
+    /// Wait for the snapshot being written in the background, if any.
+    fn finish_checkpoint(&mut self) {
+        let Some(handle) = self.checkpoint.take() else {
+            return;
+        };
+
+        match handle.join() {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::error!(?err, dir=?self.dir, "Failed to checkpoint room."),
+            Err(_) => tracing::error!(dir=?self.dir, "Checkpoint thread panicked."),
+        }
+    }
+
+    /// Write the current state as a snapshot and start a new write-ahead log.
+    #[cfg(test)]
+    fn checkpoint(&mut self) -> Result<()> {
+        self.finish_checkpoint();
+        self.start_checkpoint()?.write(&self.dir)
+    }
+
+    /// Start a checkpoint in the background once the log is long enough,
+    /// unless the previous one is still being written.
+    fn maybe_checkpoint(&mut self) {
+        if self.wal_records < CHECKPOINT_RECORDS {
+            return;
+        }
+        if let Some(handle) = &self.checkpoint {
+            if !handle.is_finished() {
+                return;
+            }
+        }
+        self.finish_checkpoint();
+
+        match self.start_checkpoint() {
+            Ok(checkpoint) => {
+                let dir = self.dir.clone();
+                self.checkpoint = Some(std::thread::spawn(move || checkpoint.write(&dir)));
+            }
+            Err(err) => tracing::error!(?err, dir=?self.dir, "Failed to checkpoint room."),
+        }
+    }
+
+    fn log(&mut self, result: ApplyResult) {
+        if let Err(err) = StorageBackend::apply(self, &result) {
+            tracing::error!(?err, dir=?self.dir, "Failed to write to write-ahead log.");
+        }
+    }
+}
+
+/// Remove write-ahead logs from generations before `generation`, which are
+/// already contained in the snapshot.
+fn remove_stale_logs(dir: &Path, generation: u64) -> Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+            continue;
+        };
+        let Some(log_generation) = name
+            .strip_prefix("wal-")
+            .and_then(|name| name.strip_suffix(".cbor"))
+            .and_then(|name| name.parse::<u64>().ok())
+        else {
+            continue;
+        };
+
+        if log_generation < generation {
+            std::fs::remove_file(&path)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn instruction(
+    key: &Key,
+    delete_instruction: Option<DeleteInstruction>,
+    push_instruction: Option<PushInstruction>,
+) -> ApplyResult {
+    ApplyResult {
+        key: key.clone(),
+        delete_instruction,
+        push_instruction,
+        broadcast: None,
+        stream_size: 0,
+        tombstone: None,
+        expires_at: None,
+        compacted_through: None,
+    }
+}
+
+impl Drop for DiskBackend {
+    fn drop(&mut self) {
+        self.finish_checkpoint();
+    }
+}
+
+impl StorageBackend for DiskBackend {
+    fn load(&mut self) -> SequenceNumber {
+        self.room.memory.load().max(self.room.reserved_sequence)
+    }
+
+    fn load_expirations(&mut self) -> Expirations {
+        self.room.expirations.clone()
+    }
+
+    fn set_key_ttl(&mut self, key: &Key, ttl: Option<u64>) -> Result<(), StorageError> {
+        let record = WalRecord::KeyTtl {
+            ttl_key: key.clone(),
+            ttl,
+        };
+        self.write(&record)
+            .map_err(|err| StorageError(err.to_string()))?;
+        self.room.set_key_ttl(key, ttl);
+
+        Ok(())
+    }
+
+    fn reserve_sequence(&mut self, seq: SequenceNumber) -> Result<(), StorageError> {
+        let record = WalRecord::Reserve {
+            reserved_sequence: seq,
+        };
+        self.write(&record)
+            .map_err(|err| StorageError(err.to_string()))?;
+        self.room.reserved_sequence = seq;
+
+        Ok(())
+    }
+
+    fn apply(&mut self, result: &ApplyResult) -> Result<(), StorageError> {
+        self.write(&WalRecord::Apply(Cow::Borrowed(result)))
+            .map_err(|err| StorageError(err.to_string()))?;
+        self.room.apply(result);
+        self.maybe_checkpoint();
+
+        Ok(())
+    }
+
+    fn flush(&mut self) {
+        if let Err(err) = self.sync() {
+            tracing::error!(?err, dir=?self.dir, "Failed to sync write-ahead log.");
+        }
+    }
+
+    fn append(&mut self, key: &Key, value: SequenceValue) {
+        self.log(instruction(key, None, Some(PushInstruction::Push(value))));
+    }
+
+    fn push_front(&mut self, key: &Key, value: SequenceValue) {
+        self.log(instruction(
+            key,
+            None,
+            Some(PushInstruction::PushStart(value)),
+        ));
+    }
+
+    fn set_compacted_through(&mut self, key: &Key, seq: SequenceNumber) {
+        let mut result = instruction(key, None, None);
+        result.compacted_through = Some(seq);
+        self.log(result);
+    }
+
+    fn delete(&mut self, key: &Key) {
+        self.log(instruction(key, Some(DeleteInstruction::Delete), None));
+    }
+
+    fn delete_up_to(&mut self, key: &Key, seq: SequenceNumber) {
+        self.log(instruction(
+            key,
+            Some(DeleteInstruction::DeleteUpTo(seq)),
+            None,
+        ));
+    }
+
+    fn get(&self, key: &Key, min_sequence: SequenceNumber) -> Vec<SequenceValue> {
+        self.room.memory.get(key, min_sequence)
+    }
+
+    fn get_range(&self, key: &Key, range: &ReadRange) -> Vec<SequenceValue> {
+        self.room.memory.get_range(key, range)
+    }
+
+    fn len(&self, key: &Key) -> usize {
+        self.room.memory.len(key)
+    }
+
+    fn seq_at(&self, key: &Key, index: usize) -> Option<SequenceNumber> {
+        self.room.memory.seq_at(key, index)
+    }
+
+    fn compacted_through(&self, key: &Key) -> Option<SequenceNumber> {
+        self.room.memory.compacted_through(key)
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.room.memory.keys()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use driftdb::{types::Action, Store};
+    use uuid::Uuid;
+
+    fn populate(store: &mut Store, key: &Key) {
+        store
+            .apply(key, Value::Integer(1.into()), &Action::Append)
+            .unwrap();
+        store
+            .apply(key, Value::Integer(2.into()), &Action::Append)
+            .unwrap();
+        store
+            .apply(key, Value::Integer(3.into()), &Action::Append)
+            .unwrap();
+        store
+            .apply(
+                key,
+                Value::Integer(12.into()),
+                &Action::Compact {
+                    seq: SequenceNumber(2),
+                },
+            )
+            .unwrap();
+        store
+            .apply(&"bar".into(), Value::Integer(4.into()), &Action::Replace)
+            .unwrap();
+    }
+
+    fn expected() -> Vec<SequenceValue> {
+        vec![
+            SequenceValue {
+                value: Value::Integer(12.into()).into(),
+                seq: SequenceNumber(2),
+            },
+            SequenceValue {
+                value: Value::Integer(3.into()).into(),
+                seq: SequenceNumber(3),
+            },
+        ]
+    }
+
+    #[test]
+    fn test_reopen_room() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let key: Key = "foo".into();
+
+        let last_seq = {
+            let backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            let mut store = Store::with_backend(backend);
+            populate(&mut store, &key);
+
+            // Neither relayed values nor tombstones are stored.
+            store
+                .apply(&key, Value::Integer(5.into()), &Action::Relay)
+                .unwrap();
+            let result = store
+                .apply(&"bar".into(), Value::Null, &Action::Delete)
+                .unwrap();
+            result.tombstone.unwrap()
+        };
+
+        let mut store = Store::with_backend(DiskBackend::open(&dir, FsyncPolicy::Always).unwrap());
+        assert_eq!(expected(), store.get(&key, SequenceNumber::default()));
+
+        // The sequence number resumes after every number handed out before.
+        let result = store
+            .apply(&key, Value::Integer(6.into()), &Action::Append)
+            .unwrap();
+        assert!(result.broadcast.unwrap().seq > last_seq);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_on_snapshot() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let key: Key = "foo".into();
+
+        {
+            let mut backend = DiskBackend::open(&dir, FsyncPolicy::Batch(4)).unwrap();
+            backend.checkpoint().unwrap();
+            let mut store = Store::with_backend(backend);
+            populate(&mut store, &key);
+            store.flush();
+        }
+
+        let seq = {
+            let mut backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            backend.checkpoint().unwrap();
+            assert_eq!(expected(), backend.get(&key, SequenceNumber::default()));
+
+            let mut store = Store::with_backend(backend);
+            let result = store
+                .apply(&key, Value::Integer(6.into()), &Action::Append)
+                .unwrap();
+            result.broadcast.unwrap().seq
+        };
+
+        // Simulate a crash part-way through writing a record.
+        let path = wal_path(&dir, 2);
+        let mut wal = OpenOptions::new().append(true).open(&path).unwrap();
+        wal.write_all(&[0xa1, 0x65]).unwrap();
+
+        let backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+        let mut values = expected();
+        values.push(SequenceValue {
+            value: Value::Integer(6.into()).into(),
+            seq,
+        });
+        assert_eq!(values, backend.get(&key, SequenceNumber::default()));
+        assert_eq!(Some(SequenceNumber(2)), backend.compacted_through(&key));
+        assert!(!wal_path(&dir, 1).exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_unfinished_checkpoint() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let key: Key = "foo".into();
+
+        {
+            let backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            let mut store = Store::with_backend(backend);
+            populate(&mut store, &key);
+        }
+
+        {
+            // Simulate a crash before the snapshot has been written.
+            let mut backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            backend.start_checkpoint().unwrap();
+            let mut store = Store::with_backend(backend);
+            store
+                .apply(&key, Value::Integer(6.into()), &Action::Append)
+                .unwrap();
+        }
+
+        let backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+        let values = backend.get(&key, SequenceNumber::default());
+        assert_eq!(expected(), values[..2]);
+        assert_eq!(Value::Integer(6.into()), *values[2].value);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reject_unwritten_change() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let key: Key = "foo".into();
+
+        let mut backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+        backend.wal = File::open(wal_path(&dir, 0)).unwrap();
+        let mut store = Store::with_backend(backend);
+
+        assert!(store
+            .apply(&key, Value::Integer(1.into()), &Action::Append)
+            .is_err());
+        assert!(store.get(&key, SequenceNumber::default()).is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_expirations() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let key: Key = "foo".into();
+
+        {
+            let backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            let mut store = Store::with_backend(backend);
+            store.set_key_ttl(&key, Some(500)).unwrap();
+            for (value, expires_at) in [(1, 1_000), (2, 2_000)] {
+                store
+                    .apply_with_expiry(
+                        &key,
+                        Value::Integer(value.into()),
+                        &Action::Append,
+                        Some(expires_at),
+                    )
+                    .unwrap();
+            }
+        }
+
+        // Replayed from the write-ahead log.
+        {
+            let mut backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            backend.checkpoint().unwrap();
+            let mut store = Store::with_backend(backend);
+            assert_eq!(Some(500), store.key_ttl(&key));
+            assert_eq!(Some(1_000), store.next_expiry());
+            assert_eq!(1, store.purge_expired(1_000).len());
+        }
+
+        // Loaded from the snapshot.
+        {
+            let backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            let mut store = Store::with_backend(backend);
+            assert_eq!(Some(500), store.key_ttl(&key));
+            assert_eq!(Some(2_000), store.next_expiry());
+
+            store.apply(&key, Value::Null, &Action::Delete).unwrap();
+        }
+
+        // Deleting the key removes its time-to-live.
+        let store = Store::with_backend(DiskBackend::open(&dir, FsyncPolicy::Always).unwrap());
+        assert_eq!(None, store.key_ttl(&key));
+        assert_eq!(None, store.next_expiry());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_replay_tagged_value() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let key: Key = "foo".into();
+        let value = Value::Tag(1, Box::new(Value::Integer(1_700_000_000.into())));
+
+        {
+            let backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            let mut store = Store::with_backend(backend);
+            store.apply(&key, value.clone(), &Action::Append).unwrap();
+        }
+
+        let backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+        let values = backend.get(&key, SequenceNumber::default());
+        assert_eq!(1, values.len());
+        assert_eq!(value, *values[0].value);
+        drop(backend);
+
+        // A record which is complete but can not be decoded is not discarded.
+        let path = wal_path(&dir, 0);
+        let len = std::fs::metadata(&path).unwrap().len();
+        let mut wal = OpenOptions::new().append(true).open(&path).unwrap();
+        wal.write_all(&[0xa1, 0x63, b'f', b'o', b'o', 0x00])
+            .unwrap();
+
+        assert!(DiskBackend::open(&dir, FsyncPolicy::Always).is_err());
+        assert_eq!(len + 6, std::fs::metadata(&path).unwrap().len());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_reopen_restored_watermark() {
+        let dir = std::env::temp_dir().join(Uuid::new_v4().to_string());
+        let key: Key = "foo".into();
+
+        // Compact, then trim the compacted value.
+        let mut source = Store::default();
+        populate(&mut source, &key);
+        source
+            .apply(
+                &key,
+                Value::Integer(4.into()),
+                &Action::AppendCapped { max_len: 2 },
+            )
+            .unwrap();
+        let snapshot = source.snapshot();
+
+        {
+            let backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            let mut store = Store::with_backend(backend);
+            store.restore(snapshot.clone()).unwrap();
+        }
+
+        // Replayed from the write-ahead log.
+        {
+            let mut backend = DiskBackend::open(&dir, FsyncPolicy::Always).unwrap();
+            assert_eq!(Some(SequenceNumber(2)), backend.compacted_through(&key));
+            backend.checkpoint().unwrap();
+        }
+
+        // Loaded from the snapshot.
+        let store = Store::with_backend(DiskBackend::open(&dir, FsyncPolicy::Always).unwrap());
+        assert_eq!(snapshot.keys, store.snapshot().keys);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
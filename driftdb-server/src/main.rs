@@ -1,8 +1,10 @@
 #![doc = include_str!("../README.md")]
 
 use crate::server::run_server;
-use clap::Parser;
-use std::net::IpAddr;
+use clap::{Parser, ValueEnum};
+use disk::FsyncPolicy;
+use outbox::{OutboxConfig, SlowConsumerPolicy};
+use std::{net::IpAddr, path::PathBuf, time::Duration};
 use tracing_subscriber::{
     filter::{EnvFilter, LevelFilter},
     fmt,
@@ -10,6 +12,8 @@ use tracing_subscriber::{
     util::SubscriberInitExt,
 };
 
+mod disk;
+mod outbox;
 mod server;
 
 #[derive(Parser)]
@@ -19,6 +23,69 @@ pub struct Opts {
 
     #[clap(long, default_value = "127.0.0.1")]
     host: IpAddr,
+
+    /// Persist rooms to this directory so that they survive restarts.
+    #[clap(long)]
+    data_dir: Option<PathBuf>,
+
+    /// When to sync each room's write-ahead log to disk.
+    #[clap(long, value_enum, default_value = "always")]
+    fsync: FsyncMode,
+
+    /// Number of records per sync when `--fsync batch` is used.
+    #[clap(long, default_value = "64")]
+    fsync_batch_size: usize,
+
+    /// Milliseconds between syncs when `--fsync interval` is used.
+    #[clap(long, default_value = "1000")]
+    fsync_interval_ms: u64,
+
+    /// What to do when a client falls behind on reading messages.
+    #[clap(long, value_enum, default_value = "buffer")]
+    slow_consumer: SlowConsumerMode,
+
+    /// Maximum number of bytes of messages to buffer for each client.
+    #[clap(long, default_value = "4194304")]
+    send_buffer_bytes: usize,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum FsyncMode {
+    Always,
+    Batch,
+    Interval,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum SlowConsumerMode {
+    Disconnect,
+    Buffer,
+    DropRelay,
+}
+
+impl Opts {
+    fn fsync_policy(&self) -> FsyncPolicy {
+        match self.fsync {
+            FsyncMode::Always => FsyncPolicy::Always,
+            FsyncMode::Batch => FsyncPolicy::Batch(self.fsync_batch_size),
+            FsyncMode::Interval => {
+                FsyncPolicy::Interval(Duration::from_millis(self.fsync_interval_ms))
+            }
+        }
+    }
+
+    fn outbox_config(&self) -> OutboxConfig {
+        let policy = match self.slow_consumer {
+            SlowConsumerMode::Disconnect => SlowConsumerPolicy::Disconnect,
+            SlowConsumerMode::Buffer => SlowConsumerPolicy::Buffer,
+            SlowConsumerMode::DropRelay => SlowConsumerPolicy::DropRelay,
+        };
+
+        OutboxConfig {
+            policy,
+            budget: self.send_buffer_bytes,
+        }
+    }
 }
 
 #[tokio::main]
@@ -0,0 +1,375 @@
+use axum::extract::ws::Message;
+use driftdb::{Encoding, Frame, Key, MessageFromDatabase};
+use std::{
+    collections::{BTreeSet, VecDeque},
+    sync::{Mutex, MutexGuard},
+};
+use tokio::sync::Notify;
+
+/// What to do when a client does not read messages as fast as they are sent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// Close the connection once the buffer is full.
+    Disconnect,
+
+    /// Drop messages once the buffer is full, then tell the client which keys
+    /// it needs to `Get` again once it has caught up. Hints, such as stream
+    /// sizes, are dropped without a resync. Messages which a `Get` can not
+    /// recover, such as responses to requests, close the connection instead.
+    Buffer,
+
+    /// Drop relayed messages and hints once the buffer is full. Durable messages are
+    /// not dropped until they exceed the buffer `DURABLE_BUDGET_FACTOR` times
+    /// over, at which point the connection is closed.
+    DropRelay,
+}
+
+/// How many times over its budget a client's buffer may grow with durable
+/// messages under [`SlowConsumerPolicy::DropRelay`].
+const DURABLE_BUDGET_FACTOR: usize = 4;
+
+#[derive(Clone, Copy, Debug)]
+pub struct OutboxConfig {
+    pub policy: SlowConsumerPolicy,
+
+    /// Maximum number of bytes of encoded messages to buffer for a client.
+    pub budget: usize,
+}
+
+/// The next thing to do with a client's socket.
+#[derive(Debug, PartialEq)]
+pub enum Outgoing {
+    Send(Message),
+
+    /// The client fell behind and should be disconnected.
+    Disconnect,
+}
+
+#[derive(Default)]
+struct OutboxState {
+    queue: VecDeque<Message>,
+    bytes: usize,
+
+    /// Keys for which messages were dropped since the client last caught up.
+    dropped_keys: BTreeSet<Key>,
+
+    overflowed: bool,
+}
+
+/// Encoded messages waiting to be sent to a single client.
+pub struct Outbox {
+    config: OutboxConfig,
+    encoding: Encoding,
+    state: Mutex<OutboxState>,
+    notify: Notify,
+}
+
+impl Outbox {
+    pub fn new(config: OutboxConfig, cbor: bool) -> Self {
+        Self {
+            config,
+            encoding: if cbor { Encoding::Cbor } else { Encoding::Json },
+            state: Mutex::default(),
+            notify: Notify::new(),
+        }
+    }
+
+    /// Encode a frame, reusing its encoding if another connection has already
+    /// encoded it.
+    fn encode(&self, frame: &Frame) -> anyhow::Result<Message> {
+        let encoded = frame.encode(self.encoding)?;
+        match self.encoding {
+            Encoding::Cbor => Ok(Message::Binary(encoded.to_vec())),
+            Encoding::Json => Ok(Message::Text(String::from_utf8(encoded.to_vec())?)),
+        }
+    }
+
+    /// Queue a message, applying the slow consumer policy if the buffer is full.
+    pub fn push(&self, frame: &Frame) {
+        let message = frame.message();
+        let encoded = match self.encode(frame) {
+            Ok(encoded) => encoded,
+            Err(err) => {
+                tracing::error!(?err, "Failed to encode message.");
+                return;
+            }
+        };
+        let size = message_len(&encoded);
+
+        let mut state = self.state.lock().unwrap();
+        if state.overflowed {
+            return;
+        }
+
+        let over_budget = state.bytes + size > self.config.budget;
+        match self.config.policy {
+            SlowConsumerPolicy::Disconnect => {
+                if over_budget {
+                    return self.overflow(state);
+                }
+            }
+            SlowConsumerPolicy::Buffer => match message_keys(message) {
+                // Once a message for a key is dropped, later ones are dropped
+                // too, so that none arrive before the client has resynced.
+                Some(keys)
+                    if over_budget || keys.iter().any(|key| state.dropped_keys.contains(key)) =>
+                {
+                    state.dropped_keys.extend(resync_keys(message));
+                    return;
+                }
+                None if over_budget => return self.overflow(state),
+                _ => {}
+            },
+            SlowConsumerPolicy::DropRelay => {
+                if over_budget && is_droppable(message) {
+                    return;
+                }
+                if state.bytes + size > self.config.budget * DURABLE_BUDGET_FACTOR {
+                    return self.overflow(state);
+                }
+            }
+        }
+
+        state.bytes += size;
+        state.queue.push_back(encoded);
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    /// Give up on the client, discarding everything queued for it.
+    fn overflow(&self, mut state: MutexGuard<OutboxState>) {
+        state.overflowed = true;
+        state.queue.clear();
+        state.bytes = 0;
+        drop(state);
+        self.notify.notify_one();
+    }
+
+    fn pop(&self) -> Option<Outgoing> {
+        let mut state = self.state.lock().unwrap();
+        if state.overflowed {
+            return Some(Outgoing::Disconnect);
+        }
+
+        if let Some(message) = state.queue.pop_front() {
+            state.bytes -= message_len(&message);
+            return Some(Outgoing::Send(message));
+        }
+
+        if !state.dropped_keys.is_empty() {
+            let keys = std::mem::take(&mut state.dropped_keys);
+            let frame = Frame::new(MessageFromDatabase::ResyncRequired {
+                keys: keys.into_iter().collect(),
+            });
+            match self.encode(&frame) {
+                Ok(encoded) => return Some(Outgoing::Send(encoded)),
+                Err(err) => tracing::error!(?err, "Failed to encode message."),
+            }
+        }
+
+        None
+    }
+
+    /// Wait for the next message to send to the client.
+    pub async fn next(&self) -> Outgoing {
+        loop {
+            if let Some(outgoing) = self.pop() {
+                return outgoing;
+            }
+
+            self.notify.notified().await;
+        }
+    }
+}
+
+fn message_len(message: &Message) -> usize {
+    match message {
+        Message::Text(text) => text.len(),
+        Message::Binary(bytes) => bytes.len(),
+        _ => 0,
+    }
+}
+
+/// Whether a message is only a hint, which is sent again as the stream grows:
+/// compaction requests, and stream sizes, which are informational.
+fn is_hint(message: &MessageFromDatabase) -> bool {
+    matches!(
+        message,
+        MessageFromDatabase::CompactionRequested { .. } | MessageFromDatabase::StreamSize { .. }
+    )
+}
+
+/// Whether a message can be dropped without the client having to resync.
+/// Relayed messages are not retained, and hints are sent again.
+fn is_droppable(message: &MessageFromDatabase) -> bool {
+    match message {
+        MessageFromDatabase::Push { relay, .. } => *relay,
+        MessageFromDatabase::Batch { messages } => messages.iter().all(is_droppable),
+        message => is_hint(message),
+    }
+}
+
+/// The keys of the streams a message belongs to, or `None` if it contains a
+/// message which is not part of a stream and so can not be dropped. Hints
+/// belong to no stream, since dropping them needs no resync.
+fn message_keys(message: &MessageFromDatabase) -> Option<Vec<Key>> {
+    match message {
+        message if is_hint(message) => Some(vec![]),
+        MessageFromDatabase::Push { key, .. }
+        | MessageFromDatabase::Init { key, .. }
+        | MessageFromDatabase::InitChunk { key, .. }
+        | MessageFromDatabase::Deleted { key, .. }
+        | MessageFromDatabase::Compacted { key, .. } => Some(vec![key.clone()]),
+        MessageFromDatabase::Batch { messages } => messages
+            .iter()
+            .map(message_keys)
+            .collect::<Option<Vec<_>>>()
+            .map(|keys| keys.concat()),
+        _ => None,
+    }
+}
+
+/// The keys which a client that missed a message should `Get` again. Relayed
+/// messages are not retained, so a `Get` would not recover them.
+fn resync_keys(message: &MessageFromDatabase) -> Vec<Key> {
+    match message {
+        MessageFromDatabase::Push { relay: true, .. } => vec![],
+        MessageFromDatabase::Batch { messages } => messages.iter().flat_map(resync_keys).collect(),
+        _ => message_keys(message).unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ciborium::Value;
+    use driftdb::types::SequenceNumber;
+
+    fn push(key: &str, relay: bool) -> Frame {
+        Frame::new(MessageFromDatabase::Push {
+            key: key.into(),
+            value: Value::Text("x".repeat(100)).into(),
+            seq: SequenceNumber(1),
+            relay,
+        })
+    }
+
+    fn outbox(policy: SlowConsumerPolicy) -> Outbox {
+        Outbox::new(
+            OutboxConfig {
+                policy,
+                budget: 150,
+            },
+            false,
+        )
+    }
+
+    fn drain(outbox: &Outbox) -> Vec<Outgoing> {
+        std::iter::from_fn(|| outbox.pop()).take(10).collect()
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let outbox = outbox(SlowConsumerPolicy::Disconnect);
+        outbox.push(&push("a", false));
+        outbox.push(&push("b", false));
+
+        assert_eq!(Some(Outgoing::Disconnect), outbox.pop());
+    }
+
+    #[test]
+    fn test_buffer_requires_resync() {
+        let outbox = outbox(SlowConsumerPolicy::Buffer);
+        outbox.push(&push("a", false));
+        outbox.push(&push("b", false));
+        outbox.push(&push("c", true));
+
+        let resync = outbox
+            .encode(&Frame::new(MessageFromDatabase::ResyncRequired {
+                keys: vec!["b".into()],
+            }))
+            .unwrap();
+        let outgoing = drain(&outbox);
+        assert_eq!(2, outgoing.len());
+        assert_eq!(Outgoing::Send(resync), outgoing[1]);
+    }
+
+    #[test]
+    fn test_buffer_drops_key_until_resync() {
+        let outbox = outbox(SlowConsumerPolicy::Buffer);
+        outbox.push(&push("a", false));
+        outbox.push(&push("b", false));
+
+        // There is room again, but "b" still needs to be resynced first.
+        assert!(matches!(outbox.pop(), Some(Outgoing::Send(_))));
+        outbox.push(&push("b", false));
+        outbox.push(&push("c", false));
+
+        let resync = outbox
+            .encode(&Frame::new(MessageFromDatabase::ResyncRequired {
+                keys: vec!["b".into()],
+            }))
+            .unwrap();
+        let outgoing = drain(&outbox);
+        assert_eq!(2, outgoing.len());
+        assert_eq!(Outgoing::Send(resync), outgoing[1]);
+
+        outbox.push(&push("b", false));
+        assert_eq!(1, drain(&outbox).len());
+    }
+
+    #[test]
+    fn test_buffer_disconnects_on_response() {
+        let outbox = outbox(SlowConsumerPolicy::Buffer);
+        let pong = Frame::new(MessageFromDatabase::Pong { nonce: None });
+        outbox.push(&pong);
+        assert_eq!(1, drain(&outbox).len());
+
+        outbox.push(&push("a", false));
+        outbox.push(&pong);
+        assert_eq!(Some(Outgoing::Disconnect), outbox.pop());
+    }
+
+    #[test]
+    fn test_hints_are_dropped() {
+        let hint = Frame::new(MessageFromDatabase::CompactionRequested {
+            key: "a".into(),
+            size: 2,
+        });
+
+        // Neither a disconnect nor a resync.
+        let buffer = outbox(SlowConsumerPolicy::Buffer);
+        buffer.push(&push("a", false));
+        buffer.push(&hint);
+        assert_eq!(1, drain(&buffer).len());
+
+        let drop_relay = outbox(SlowConsumerPolicy::DropRelay);
+        drop_relay.push(&push("a", false));
+        drop_relay.push(&Frame::new(MessageFromDatabase::StreamSize {
+            key: "a".into(),
+            size: 2,
+        }));
+        drop_relay.push(&push("b", false));
+        assert_eq!(2, drain(&drop_relay).len());
+    }
+
+    #[test]
+    fn test_drop_relay() {
+        let outbox = outbox(SlowConsumerPolicy::DropRelay);
+        outbox.push(&push("a", false));
+        outbox.push(&push("b", true));
+        outbox.push(&push("c", false));
+
+        assert_eq!(2, drain(&outbox).len());
+    }
+
+    #[test]
+    fn test_drop_relay_limits_durable() {
+        let outbox = outbox(SlowConsumerPolicy::DropRelay);
+        for _ in 0..DURABLE_BUDGET_FACTOR * 2 {
+            outbox.push(&push("a", false));
+        }
+
+        assert_eq!(Some(Outgoing::Disconnect), outbox.pop());
+    }
+}
@@ -1,18 +1,28 @@
-use crate::Opts;
+use crate::{
+    disk::{DiskBackend, FsyncPolicy},
+    outbox::{Outbox, OutboxConfig, Outgoing},
+    Opts,
+};
 use anyhow::Result;
 use axum::{
-    body::BoxBody,
-    extract::{ws::WebSocket, Host, Path, Query, State, WebSocketUpgrade},
-    response::Response,
+    body::{BoxBody, Bytes},
+    extract::{
+        ws::{close_code, CloseFrame, Message, WebSocket},
+        Host, Path, Query, State, WebSocketUpgrade,
+    },
+    http::HeaderMap,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use dashmap::DashMap;
-use driftdb::{Database, MessageFromDatabase, MessageToDatabase};
+use driftdb::{
+    Database, Error, Frame, MessageFromDatabase, Request, Snapshot, SnapshotError, Store,
+};
 use hyper::http::header;
 use hyper::{Method, StatusCode};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use std::{fmt::Debug, net::SocketAddr, sync::Arc};
+use std::{fmt::Debug, net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 use tower_http::{
     cors::{AllowOrigin, CorsLayer},
     trace::{DefaultMakeSpan, DefaultOnRequest, DefaultOnResponse, TraceLayer},
@@ -85,54 +95,68 @@ impl<Inbound: DeserializeOwned + Debug, Outbound: Serialize + Debug>
 
         Ok(())
     }
+
+    /// Send a message which has already been encoded.
+    pub async fn send_raw(&mut self, msg: Message) -> Result<()> {
+        self.socket.send(msg).await?;
+        Ok(())
+    }
 }
 
 async fn handle_socket(
     socket: WebSocket,
     database: Arc<Database>,
     connection_spec: ConnectionQuery,
+    outbox_config: OutboxConfig,
 ) {
-    let (sender, mut receiver) = tokio::sync::mpsc::channel(32);
-    let mut socket: TypedWebSocket<MessageToDatabase, MessageFromDatabase> =
+    let outbox = Arc::new(Outbox::new(outbox_config, connection_spec.cbor));
+    let mut socket: TypedWebSocket<Request, MessageFromDatabase> =
         TypedWebSocket::new(socket, connection_spec.cbor);
 
-    let callback = move |message: &MessageFromDatabase| {
-        let result = sender.try_send(message.clone());
-
-        if let Err(err) = result {
-            tracing::error!(
-                ?err,
-                "Failed to send message to user, probably already closed."
-            );
-        }
+    let callback = {
+        let outbox = outbox.clone();
+        move |frame: &Frame| outbox.push(frame)
     };
 
     let conn = if connection_spec.debug {
-        database.connect_debug(callback)
+        database.connect_debug_frames(callback)
     } else {
-        database.connect(callback)
+        database.connect_frames(callback)
     };
 
     loop {
         tokio::select! {
-            msg = receiver.recv() => {
+            outgoing = outbox.next() => {
                 // We've received a message from the database; forward it to user.
 
-                let msg = msg.expect("Receiver should never be dropped before socket is closed.");
+                match outgoing {
+                    Outgoing::Send(msg) => {
+                        if let Err(err) = socket.send_raw(msg).await {
+                            tracing::warn!(?err, "Failed to send message to user.");
+                            break;
+                        }
+                    }
+                    Outgoing::Disconnect => {
+                        tracing::warn!("Disconnecting client which fell behind.");
+
+                        let _ = socket.send_raw(Message::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "Client is not reading messages fast enough.".into(),
+                        }))).await;
 
-                socket.send(msg).await.expect("Failed to send message to user.");
+                        break;
+                    }
+                }
             }
             msg = socket.recv() => {
                 // We've received a message from the client; forward it to the database.
 
                 match msg {
-                    Ok(Some(msg)) => {
-                        if let Err(e) = conn.send_message(&msg) {
+                    Ok(Some(request)) => {
+                        if let Err(e) = conn.send_request(&request) {
                             tracing::error!(?e, "Failed to send message to database.");
 
-                            let _ = socket.send(MessageFromDatabase::Error {
-                                message: format!("Failed to send message to database: {}", e),
-                            }).await;
+                            let _ = socket.send(e.to_message(request.request_id)).await;
                         }
                     },
                     Ok(None) => {
@@ -142,9 +166,7 @@ async fn handle_socket(
                     Err(err) => {
                         tracing::warn!(?err, "Failed to receive message from user.");
 
-                        let _ = socket.send(MessageFromDatabase::Error {
-                            message: format!("Failed to receive message from user: {}", err),
-                        }).await;
+                        let _ = socket.send(Error::Decode(err.to_string()).to_message(None)).await;
 
                         break;
                     }
@@ -163,43 +185,188 @@ struct ConnectionQuery {
     cbor: bool,
 }
 
-type RoomMap = DashMap<String, Arc<Database>>;
+struct RoomMap {
+    rooms: DashMap<String, Arc<Database>>,
+
+    /// Directory in which rooms are persisted, if persistence is enabled.
+    data_dir: Option<PathBuf>,
+
+    fsync: FsyncPolicy,
+
+    /// How to buffer messages for clients.
+    outbox: OutboxConfig,
+}
+
+impl RoomMap {
+    fn new(data_dir: Option<PathBuf>, fsync: FsyncPolicy, outbox: OutboxConfig) -> Self {
+        Self {
+            rooms: DashMap::new(),
+            data_dir,
+            fsync,
+            outbox,
+        }
+    }
+
+    fn open_database(&self, room_id: &str) -> Result<Database> {
+        let Some(data_dir) = &self.data_dir else {
+            return Ok(Database::new());
+        };
+
+        let backend = DiskBackend::open(&data_dir.join(room_id), self.fsync)?;
+        Ok(Database::new_from_store(Store::with_backend(backend)))
+    }
+
+    fn create(&self) -> Result<String> {
+        let room_id = Uuid::new_v4().to_string();
+        let database = Arc::new(self.open_database(&room_id)?);
+        self.rooms.insert(room_id.clone(), database);
+
+        Ok(room_id)
+    }
+
+    /// Get a room, loading it from the data directory if it is not yet in memory.
+    fn get(&self, room_id: &str) -> Option<Arc<Database>> {
+        if let Some(database) = self.rooms.get(room_id) {
+            return Some(database.clone());
+        }
+
+        let data_dir = self.data_dir.as_ref()?;
+        // Room IDs are always UUIDs; anything else must not be used as a path.
+        Uuid::parse_str(room_id).ok()?;
+        if !data_dir.join(room_id).is_dir() {
+            return None;
+        }
+
+        let entry = self
+            .rooms
+            .entry(room_id.to_string())
+            .or_try_insert_with(|| {
+                tracing::info!(room_id, "Loading room from disk.");
+                self.open_database(room_id).map(Arc::new)
+            });
+
+        match entry {
+            Ok(database) => Some(database.clone()),
+            Err(err) => {
+                tracing::error!(?err, room_id, "Failed to load room from disk.");
+                None
+            }
+        }
+    }
+}
 
 async fn post_message(
     Path(room_id): Path<String>,
     State(room_map): State<Arc<RoomMap>>,
-    Json(msg): Json<MessageToDatabase>,
-) -> std::result::Result<Json<Option<MessageFromDatabase>>, StatusCode> {
-    let database = room_map.get(&room_id).ok_or(StatusCode::NOT_FOUND)?;
+    Json(request): Json<Request>,
+) -> std::result::Result<Json<Option<MessageFromDatabase>>, Response<BoxBody>> {
+    let database = room_map
+        .get(&room_id)
+        .ok_or_else(|| StatusCode::NOT_FOUND.into_response())?;
     let conn = database.connect(|_| {});
 
-    let result = conn.send_message(&msg).unwrap();
+    // The connection is dropped after this request, so it should not subscribe.
+    let request = request.without_subscription();
+    let result = conn.send_request(&request).map_err(|err| {
+        (
+            StatusCode::BAD_REQUEST,
+            Json(err.to_message(request.request_id)),
+        )
+            .into_response()
+    })?;
 
     Ok(Json(result))
 }
 
+#[derive(Deserialize)]
+struct ExportQuery {
+    #[serde(default)]
+    cbor: bool,
+}
+
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+async fn export_room(
+    Path(room_id): Path<String>,
+    State(room_map): State<Arc<RoomMap>>,
+    Query(query): Query<ExportQuery>,
+) -> std::result::Result<Response<BoxBody>, StatusCode> {
+    let database = room_map.get(&room_id).ok_or(StatusCode::NOT_FOUND)?;
+    let snapshot = database.snapshot();
+
+    let (content_type, body) = if query.cbor {
+        (CBOR_CONTENT_TYPE, snapshot.to_cbor())
+    } else {
+        (
+            NDJSON_CONTENT_TYPE,
+            snapshot.to_ndjson().map(String::into_bytes),
+        )
+    };
+    let body = body.map_err(|err| {
+        tracing::error!(?err, "Failed to encode snapshot.");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    Ok(([(header::CONTENT_TYPE, content_type)], body).into_response())
+}
+
+async fn import_room(
+    Path(room_id): Path<String>,
+    State(room_map): State<Arc<RoomMap>>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> std::result::Result<StatusCode, (StatusCode, String)> {
+    let database = room_map
+        .get(&room_id)
+        .ok_or((StatusCode::NOT_FOUND, "Room not found.".to_string()))?;
+
+    let is_cbor = headers
+        .get(header::CONTENT_TYPE)
+        .map(|content_type| content_type == CBOR_CONTENT_TYPE)
+        .unwrap_or(false);
+
+    let snapshot = if is_cbor {
+        Snapshot::from_cbor(&body)
+    } else {
+        let text =
+            std::str::from_utf8(&body).map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+        Snapshot::from_ndjson(text)
+    };
+    let snapshot = snapshot.map_err(|err| (StatusCode::BAD_REQUEST, err.to_string()))?;
+
+    database.restore(snapshot).map_err(|err| match err {
+        SnapshotError::Storage(_) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()),
+        _ => (StatusCode::BAD_REQUEST, err.to_string()),
+    })?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
 async fn connection(
     Path(room_id): Path<String>,
     ws: WebSocketUpgrade,
     State(room_map): State<Arc<RoomMap>>,
     Query(query): Query<ConnectionQuery>,
-) -> Response<BoxBody> {
-    let database = room_map
-        .get(&room_id)
-        .expect("Room should have been created before connection.")
-        .clone();
+) -> std::result::Result<Response<BoxBody>, StatusCode> {
+    let database = room_map.get(&room_id).ok_or(StatusCode::NOT_FOUND)?;
 
-    ws.on_upgrade(move |socket| handle_socket(socket, database, query))
+    let outbox = room_map.outbox;
+    Ok(ws.on_upgrade(move |socket| handle_socket(socket, database, query, outbox)))
 }
 
-async fn new_room(Host(hostname): Host, State(room_map): State<Arc<RoomMap>>) -> Json<RoomResult> {
-    let room = Uuid::new_v4().to_string();
-    let database = Arc::new(Database::new());
-    room_map.insert(room.clone(), database);
+async fn new_room(
+    Host(hostname): Host,
+    State(room_map): State<Arc<RoomMap>>,
+) -> std::result::Result<Json<RoomResult>, StatusCode> {
+    let room = room_map.create().map_err(|err| {
+        tracing::error!(?err, "Failed to create room.");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
 
     let result = RoomResult::new(room, &hostname);
 
-    Json(result)
+    Ok(Json(result))
 }
 
 async fn room(
@@ -234,7 +401,42 @@ impl RoomResult {
     }
 }
 
-pub fn api_routes() -> Result<Router> {
+/// Periodically sync every room, so that writes are not left unsynced
+/// indefinitely when the fsync policy is interval-based.
+async fn flush_rooms(room_map: Arc<RoomMap>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+
+    loop {
+        interval.tick().await;
+
+        for room in room_map.rooms.iter() {
+            room.flush();
+        }
+    }
+}
+
+/// How often expired values are purged from loaded rooms.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Periodically purge values whose time-to-live has passed from every loaded
+/// room.
+async fn sweep_expired(room_map: Arc<RoomMap>) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        for room in room_map.rooms.iter() {
+            room.purge_expired();
+        }
+    }
+}
+
+pub fn api_routes(
+    data_dir: Option<PathBuf>,
+    fsync: FsyncPolicy,
+    outbox: OutboxConfig,
+) -> Result<Router> {
     let cors = CorsLayer::new()
         .allow_methods([Method::GET, Method::POST])
         .allow_headers(vec![
@@ -244,15 +446,28 @@ pub fn api_routes() -> Result<Router> {
         ])
         .allow_origin(AllowOrigin::any());
 
-    let room_map = RoomMap::new();
+    if let Some(data_dir) = &data_dir {
+        std::fs::create_dir_all(data_dir)?;
+        tracing::info!(?data_dir, "Persisting rooms to disk.");
+    }
+
+    let room_map = Arc::new(RoomMap::new(data_dir, fsync, outbox));
+
+    tokio::spawn(sweep_expired(room_map.clone()));
+
+    if let FsyncPolicy::Interval(interval) = fsync {
+        tokio::spawn(flush_rooms(room_map.clone(), interval));
+    }
 
     Ok(Router::new()
         .route("/new", post(new_room))
         .route("/room/:room_id/connect", get(connection))
         .route("/room/:room_id/send", post(post_message))
+        .route("/room/:room_id/export", get(export_room))
+        .route("/room/:room_id/import", post(import_room))
         .route("/room/:room_id", get(room))
         .layer(cors)
-        .with_state(Arc::new(room_map)))
+        .with_state(room_map))
 }
 
 pub async fn run_server(opts: &Opts) -> anyhow::Result<()> {
@@ -261,7 +476,12 @@ pub async fn run_server(opts: &Opts) -> anyhow::Result<()> {
         .on_request(DefaultOnRequest::new().level(Level::INFO))
         .on_response(DefaultOnResponse::new().level(Level::INFO));
 
-    let app = api_routes()?.layer(trace_layer);
+    let app = api_routes(
+        opts.data_dir.clone(),
+        opts.fsync_policy(),
+        opts.outbox_config(),
+    )?
+    .layer(trace_layer);
     let addr = SocketAddr::new(opts.host, opts.port);
 
     tracing::info!(?addr, "Server is listening.");
@@ -4,14 +4,17 @@ use crate::{
     state::{PersistedDb, WrappedState},
     websocket::WrappedWebSocket,
 };
-use driftdb::{Database, MessageFromDatabase, MessageToDatabase};
+use driftdb::{Database, Error, Frame, Snapshot, SnapshotError};
 use std::collections::HashMap;
 use tokio_stream::StreamExt;
 use worker::{
     async_trait, console_warn, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures,
-    worker_sys, Env, Method, Request, Response, Result, WebSocketPair, WebsocketEvent,
+    worker_sys, Env, Headers, Method, Request, Response, Result, WebSocketPair, WebsocketEvent,
 };
 
+const CBOR_CONTENT_TYPE: &str = "application/cbor";
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
 #[durable_object]
 pub struct DbRoom {
     db: PersistedDb,
@@ -27,46 +30,46 @@ async fn receive_websocket_events(
 
     let conn = {
         let server = server.clone();
-        let callback = move |message: &MessageFromDatabase| {
-            server.send(message).expect("could not send message");
+        let callback = move |frame: &Frame| {
+            server.send_frame(frame).expect("could not send message");
         };
 
         if debug {
-            db.connect_debug(callback)
+            db.connect_debug_frames(callback)
         } else {
-            db.connect(callback)
+            db.connect_frames(callback)
         }
     };
 
     while let Some(event) = event_stream.next().await {
         match event.expect("received error in websocket") {
             WebsocketEvent::Message(msg) => {
-                if let Some(text) = msg.text() {
-                    if let Ok(message) = serde_json::from_str::<MessageToDatabase>(&text) {
-                        // Reset the timeout for cleaning up the database.
-                        state.bump_alarm().await.expect("Error bumping alarm");
-                        conn.send_message(&message).unwrap();
+                let request: std::result::Result<driftdb::Request, _> =
+                    if let Some(text) = msg.text() {
+                        serde_json::from_str(&text).map_err(|e| Error::Decode(e.to_string()))
+                    } else if let Some(bytes) = msg.bytes() {
+                        ciborium::from_reader(bytes.as_slice())
+                            .map_err(|e| Error::Decode(e.to_string()))
                     } else {
-                        server
-                            .send(&MessageFromDatabase::Error {
-                                message: format!("Could not decode message: {}", text),
-                            })
-                            .unwrap();
+                        console_warn!("Received unknown message type.");
+                        continue;
+                    };
+
+                match request {
+                    Ok(request) => {
+                        if let Err(err) = conn.send_request(&request) {
+                            server.send(&err.to_message(request.request_id)).unwrap();
+                        }
+                        // Reset the timeout for cleaning up the database, and
+                        // wake up in time to purge the next expired value.
+                        state
+                            .bump_alarm(db.next_expiry())
+                            .await
+                            .expect("Error bumping alarm");
                     }
-                } else if let Some(bytes) = msg.bytes() {
-                    if let Ok(message) = ciborium::from_reader(bytes.as_slice()) {
-                        // Reset the timeout for cleaning up the database.
-                        state.bump_alarm().await.expect("Error bumping alarm");
-                        conn.send_message(&message).unwrap();
-                    } else {
-                        server
-                            .send(&MessageFromDatabase::Error {
-                                message: format!("Could not decode message: {:?}", bytes),
-                            })
-                            .unwrap();
+                    Err(err) => {
+                        server.send(&err.to_message(None)).unwrap();
                     }
-                } else {
-                    console_warn!("Received unknown message type.");
                 }
             }
             WebsocketEvent::Close(_) => {
@@ -100,6 +103,62 @@ impl DbRoom {
 
         Response::from_websocket(client)?.with_cors(&cors())
     }
+
+    async fn export(&mut self, req: Request) -> Result<Response> {
+        let db = self.db.get_db().await?;
+        let snapshot = db.snapshot();
+
+        let url = req.url()?;
+        let use_cbor = url.query_pairs().any(|(k, v)| k == "cbor" && !v.is_empty());
+
+        let (content_type, body) = if use_cbor {
+            (CBOR_CONTENT_TYPE, snapshot.to_cbor())
+        } else {
+            (
+                NDJSON_CONTENT_TYPE,
+                snapshot.to_ndjson().map(String::into_bytes),
+            )
+        };
+        let body = body.map_err(|e| worker::Error::RustError(e.to_string()))?;
+
+        let mut headers = Headers::new();
+        headers.set("Content-Type", content_type)?;
+        Response::from_bytes(body)?
+            .with_headers(headers)
+            .with_cors(&cors())
+    }
+
+    async fn import(&mut self, mut req: Request) -> Result<Response> {
+        let db = self.db.get_db().await?;
+
+        let is_cbor = req
+            .headers()
+            .get("Content-Type")?
+            .map(|content_type| content_type == CBOR_CONTENT_TYPE)
+            .unwrap_or(false);
+
+        let snapshot = if is_cbor {
+            Snapshot::from_cbor(&req.bytes().await?)
+        } else {
+            Snapshot::from_ndjson(&req.text().await?)
+        };
+        let snapshot = match snapshot {
+            Ok(snapshot) => snapshot,
+            Err(e) => return Response::error(e.to_string(), 400)?.with_cors(&cors()),
+        };
+
+        if let Err(e) = db.restore(snapshot) {
+            let status = match e {
+                SnapshotError::Storage(_) => 500,
+                _ => 400,
+            };
+            return Response::error(e.to_string(), status)?.with_cors(&cors());
+        }
+        // Reset the timeout for cleaning up the database.
+        self.db.state.bump_alarm(db.next_expiry()).await?;
+
+        Response::empty()?.with_status(204).with_cors(&cors())
+    }
 }
 
 #[durable_object]
@@ -117,19 +176,39 @@ impl DurableObject for DbRoom {
         let method = req.method();
         match (method, path) {
             (Method::Get, "connect") => self.connect(req).await,
+            (Method::Get, "export") => self.export(req).await,
+            (Method::Post, "import") => self.import(req).await,
             (Method::Post, "send") => {
                 let db = self.db.get_db().await?;
                 let conn = db.connect(|_| {});
-                let message: MessageToDatabase = req.json().await?;
-                let response = conn.send_message(&message)?;
-                Response::from_json(&response)
+                let request: driftdb::Request = req.json().await?;
+                // The connection is dropped after this request, so it should not subscribe.
+                let request = request.without_subscription();
+                let result = conn.send_request(&request);
+                self.db.state.bump_alarm(db.next_expiry()).await?;
+                match result {
+                    Ok(response) => Response::from_json(&response),
+                    Err(err) => {
+                        Ok(Response::from_json(&err.to_message(request.request_id))?
+                            .with_status(400))
+                    }
+                }
             }
             _ => Response::error("Room command not found", 404),
         }
     }
 
     async fn alarm(&mut self) -> Result<Response> {
-        self.db.cleanup().await?;
+        let state = self.db.state.clone();
+        let last_activity = state.last_activity().await.unwrap_or_default();
+
+        if state.is_expired(last_activity) {
+            self.db.cleanup().await?;
+        } else {
+            let db = self.db.get_db().await?;
+            let next_expiry = db.purge_expired();
+            state.schedule_alarm(last_activity, next_expiry).await?;
+        }
 
         Response::ok("ok")
     }
@@ -2,17 +2,37 @@ use crate::Configuration;
 use ciborium::value::Value;
 use driftdb::{
     types::{key_seq_pair::KeyAndSeq, SequenceNumber, SequenceValue},
-    ApplyResult, Database, DeleteInstruction, Key, PushInstruction, Store, ValueLog,
+    Database, Expirations, Key, MemoryBackend, ReadRange, StorageBackend, StorageError, Store,
+    ValueLog,
 };
+use futures_channel::mpsc::{self, UnboundedReceiver, UnboundedSender};
 use gloo_utils::format::JsValueSerdeExt;
-use std::{collections::HashMap, str::FromStr, sync::Arc};
-use worker::{console_log, wasm_bindgen::JsValue, wasm_bindgen_futures};
-use worker::{ListOptions, Result, State};
+use std::{
+    collections::HashMap,
+    convert::TryFrom,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tokio_stream::StreamExt;
+use worker::{console_log, js_sys, wasm_bindgen::JsValue, wasm_bindgen_futures};
+use worker::{ListOptions, Result, State, Storage};
 
 #[derive(Clone)]
 pub struct WrappedState {
     state: Arc<State>,
     configuration: Configuration,
+    alarm: Arc<Mutex<AlarmState>>,
+}
+
+/// The room's alarm and last activity, as this instance last wrote them to
+/// storage, so that they are not written again for every message.
+#[derive(Default)]
+struct AlarmState {
+    /// When the alarm is scheduled, in milliseconds since the Unix epoch, or
+    /// `None` until it has been read from storage.
+    alarm_at: Option<Option<u64>>,
+
+    last_activity: u64,
 }
 unsafe impl Send for WrappedState {}
 unsafe impl Sync for WrappedState {}
@@ -22,16 +42,122 @@ impl WrappedState {
         Self {
             state: Arc::new(state),
             configuration,
+            alarm: Arc::default(),
+        }
+    }
+
+    /// Record activity in the room, and make sure that the alarm goes off no
+    /// later than the room's retention deadline and `next_expiry`.
+    ///
+    /// The activity is only written once per [`ACTIVITY_RESOLUTION_MS`], and
+    /// the alarm is only moved if it needs to go off earlier. An alarm which
+    /// goes off before the retention deadline reschedules itself.
+    pub async fn bump_alarm(&self, next_expiry: Option<u64>) -> Result<()> {
+        let now = now();
+
+        let last_activity = self.alarm.lock().unwrap().last_activity;
+        if now >= last_activity.saturating_add(ACTIVITY_RESOLUTION_MS) {
+            let mut storage = self.state.storage();
+            storage.put(LAST_ACTIVITY_KEY, cbor_integer(now)).await?;
+            self.alarm.lock().unwrap().last_activity = now;
+        }
+
+        let alarm_at = self.alarm_at(now, next_expiry);
+        match self.scheduled_alarm().await? {
+            Some(scheduled) if scheduled <= alarm_at => Ok(()),
+            _ => self.set_alarm(alarm_at).await,
+        }
+    }
+
+    /// Schedule the alarm for the earlier of the retention deadline of a room
+    /// last active at `last_activity`, and `next_expiry`.
+    pub async fn schedule_alarm(&self, last_activity: u64, next_expiry: Option<u64>) -> Result<()> {
+        let alarm_at = self.alarm_at(last_activity, next_expiry);
+        self.set_alarm(alarm_at).await
+    }
+
+    /// The earlier of the retention deadline of a room last active at
+    /// `last_activity`, and `next_expiry`.
+    fn alarm_at(&self, last_activity: u64, next_expiry: Option<u64>) -> u64 {
+        let retention_ms = self.configuration.retention.as_millis() as u64;
+        let deadline = last_activity.saturating_add(retention_ms);
+        next_expiry.map_or(deadline, |expiry| expiry.min(deadline))
+    }
+
+    async fn set_alarm(&self, alarm_at: u64) -> Result<()> {
+        let offset = alarm_at.saturating_sub(now()) as i64;
+        self.state.storage().set_alarm(offset).await?;
+        self.alarm.lock().unwrap().alarm_at = Some(Some(alarm_at));
+
+        Ok(())
+    }
+
+    /// When the alarm is scheduled, if it is.
+    async fn scheduled_alarm(&self) -> Result<Option<u64>> {
+        if let Some(alarm_at) = self.alarm.lock().unwrap().alarm_at {
+            return Ok(alarm_at);
         }
+
+        let alarm_at = self
+            .state
+            .storage()
+            .get_alarm()
+            .await?
+            .map(|alarm_at| alarm_at as u64);
+        self.alarm.lock().unwrap().alarm_at = Some(alarm_at);
+
+        Ok(alarm_at)
     }
 
-    pub async fn bump_alarm(&self) -> Result<()> {
-        let storage = self.state.storage();
-        let retention_ms = self.configuration.retention.as_millis() as i64;
-        storage.set_alarm(retention_ms).await
+    /// The time of the last activity in the room, in milliseconds since the
+    /// Unix epoch.
+    pub async fn last_activity(&self) -> Result<u64> {
+        let bytes: Vec<u8> = self.state.storage().get(LAST_ACTIVITY_KEY).await?;
+        let value: Value = ciborium::de::from_reader(bytes.as_slice())
+            .map_err(|_| worker::Error::RustError("Invalid last activity time.".to_string()))?;
+        value
+            .as_integer()
+            .and_then(|time| u64::try_from(time).ok())
+            .ok_or_else(|| worker::Error::RustError("Invalid last activity time.".to_string()))
+    }
+
+    /// Whether the room has been inactive for longer than its retention period.
+    pub fn is_expired(&self, last_activity: u64) -> bool {
+        let retention_ms = self.configuration.retention.as_millis() as u64;
+        now() >= last_activity.saturating_add(retention_ms)
     }
 }
 
+/// Storage key of the time of the last activity in the room. It starts with a
+/// letter, so it cannot collide with a [`KeyAndSeq`].
+const LAST_ACTIVITY_KEY: &str = "last_activity";
+
+/// Storage key of the highest sequence number reserved by the room.
+const RESERVED_SEQUENCE_KEY: &str = "reserved_sequence";
+
+/// How often the time of the last activity is written to storage. A room may
+/// be cleaned up up to this long before its retention period has passed.
+const ACTIVITY_RESOLUTION_MS: u64 = 60_000;
+
+/// Prefix of the storage key of a key's default time-to-live. It is outside
+/// the key's prefix, so it is kept when the key's values are replaced.
+const KEY_TTL_PREFIX: &str = "ttl|";
+
+/// Suffix of the storage key of a value's expiry time, which follows the
+/// value's own key. It is removed along with the value.
+const EXPIRY_SUFFIX: &str = "|expires";
+
+/// The current time, in milliseconds since the Unix epoch.
+fn now() -> u64 {
+    js_sys::Date::now() as u64
+}
+
+fn cbor_integer(value: u64) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    ciborium::ser::into_writer(&Value::Integer(value.into()), &mut buffer).unwrap();
+    buffer
+}
+
 #[cfg(all(not(target_arch = "wasm32"), not(debug_assertions)))]
 compile_error!(
     "driftdb-worker should only be compiled to WebAssembly. Use driftdb-server for other targets."
@@ -50,7 +176,10 @@ impl PersistedDb {
     }
 
     pub async fn cleanup(&mut self) -> Result<()> {
-        self.state.state.storage().delete_all().await
+        self.state.state.storage().delete_all().await?;
+        *self.state.alarm.lock().unwrap() = AlarmState::default();
+
+        Ok(())
     }
 
     pub async fn get_db(&mut self) -> Result<Database> {
@@ -61,115 +190,334 @@ impl PersistedDb {
         let state = self.state.state.as_ref();
         let result = self.load_store(state).await;
 
-        let mut db = match result {
-            Ok(store) => Database::new_from_store(store),
+        let (memory, reserved_sequence, expirations) = match result {
+            Ok(loaded) => loaded,
             Err(e) => {
                 console_log!("Error loading store: {}", e);
-                Database::new()
+                Default::default()
             }
         };
 
-        {
-            let state = self.state.clone();
-            db.set_replica_callback(move |apply_result: &ApplyResult| {
-                let mut storage = state.state.storage();
-                let apply_result = apply_result.clone();
-
-                wasm_bindgen_futures::spawn_local(async move {
-                    if let Some(delete_instruction) = &apply_result.delete_instruction {
-                        match delete_instruction {
-                            DeleteInstruction::Delete => {
-                                let prefix = KeyAndSeq::prefix_str(&apply_result.key);
-                                let list_options = ListOptions::new().prefix(&prefix);
-
-                                let result = storage.list_with_options(list_options).await;
-
-                                if let Ok(keys) = result {
-                                    let keys: Vec<String> = keys
-                                        .keys()
-                                        .into_iter()
-                                        .map(|d| d.unwrap().as_string().unwrap())
-                                        .collect();
-                                    storage
-                                        .delete_multiple(keys)
-                                        .await
-                                        .expect("Error deleting keys.");
-                                }
-                            }
-                            DeleteInstruction::DeleteUpTo(seq) => {
-                                let prefix = KeyAndSeq::prefix_str(&apply_result.key);
-                                let end = KeyAndSeq::new(apply_result.key.clone(), seq.next())
-                                    .to_string();
-                                let list_options = ListOptions::new().prefix(&prefix).end(&end);
-                                let result = storage.list_with_options(list_options).await;
-
-                                if let Ok(keys) = result {
-                                    let keys: Vec<String> = keys
-                                        .keys()
-                                        .into_iter()
-                                        .map(|d| d.unwrap().as_string().unwrap())
-                                        .collect();
-                                    storage
-                                        .delete_multiple(keys)
-                                        .await
-                                        .expect("Error deleting keys.");
-                                }
-                            }
-                        }
-                    }
-
-                    if let Some(push_instruction) = &apply_result.push_instruction {
-                        let sequence_value = match push_instruction {
-                            PushInstruction::Push(sequence_value) => sequence_value,
-                            PushInstruction::PushStart(sequence_value) => sequence_value,
-                        };
-
-                        let storage_key =
-                            KeyAndSeq::new(apply_result.key.clone(), sequence_value.seq)
-                                .to_string();
-
-                        let mut buffer = Vec::new();
-                        ciborium::ser::into_writer(&sequence_value.value, &mut buffer).unwrap();
-
-                        storage
-                            .put(&storage_key, &buffer)
-                            .await
-                            .expect("Error putting value in storage.");
-                    }
-                });
-            });
-        }
+        let backend =
+            DurableObjectBackend::new(self.state.clone(), memory, reserved_sequence, expirations);
+        let store = Store::with_backend(backend);
+        let mut db = Database::new_from_store(store);
+        db.set_clock(now);
 
         self.db = Some(db);
         Ok(self.db.clone().unwrap())
     }
 
-    async fn load_store(&self, state: &State) -> Result<Store> {
+    async fn load_store(
+        &self,
+        state: &State,
+    ) -> Result<(MemoryBackend, SequenceNumber, Expirations)> {
         let storage = state.storage();
         let mut subjects = HashMap::<Key, ValueLog>::new();
+        let mut reserved_sequence = SequenceNumber::default();
+        let mut expirations = Expirations::default();
         let data = storage.list().await?;
 
-        let mut max_seq = 0;
-
         for kv in data.entries() {
             let kv = kv?;
 
             let (value, key) = read_key_value(&kv)?;
 
+            if key == LAST_ACTIVITY_KEY {
+                continue;
+            }
+
+            if key == RESERVED_SEQUENCE_KEY {
+                let seq = value
+                    .as_integer()
+                    .and_then(|seq| u64::try_from(seq).ok())
+                    .ok_or_else(|| {
+                        worker::Error::RustError("Invalid reserved sequence number.".to_string())
+                    })?;
+                reserved_sequence = SequenceNumber(seq);
+                continue;
+            }
+
+            if let Some(key) = key.strip_prefix(KEY_TTL_PREFIX) {
+                let ttl = value
+                    .as_integer()
+                    .and_then(|ttl| u64::try_from(ttl).ok())
+                    .ok_or_else(|| worker::Error::RustError("Invalid time-to-live.".to_string()))?;
+                expirations.key_ttls.insert(Key::new(key.to_string()), ttl);
+                continue;
+            }
+
+            if let Some(key_and_seq) = key.strip_suffix(EXPIRY_SUFFIX) {
+                let key_and_seq = KeyAndSeq::from_str(key_and_seq)?;
+                let expires_at = value
+                    .as_integer()
+                    .and_then(|time| u64::try_from(time).ok())
+                    .ok_or_else(|| worker::Error::RustError("Invalid expiry time.".to_string()))?;
+                expirations
+                    .values
+                    .entry(key_and_seq.key)
+                    .or_default()
+                    .insert(key_and_seq.seq, expires_at);
+                continue;
+            }
+
+            if let Some(key) = parse_compaction_marker(&key) {
+                let seq = value
+                    .as_integer()
+                    .and_then(|seq| u64::try_from(seq).ok())
+                    .ok_or_else(|| {
+                        worker::Error::RustError("Invalid compaction marker.".to_string())
+                    })?;
+                subjects.entry(key).or_default().compacted_through = Some(SequenceNumber(seq));
+                continue;
+            }
+
             let key_and_seq = KeyAndSeq::from_str(&key)?;
-            max_seq = max_seq.max(key_and_seq.seq.0);
 
             subjects
                 .entry(key_and_seq.key)
                 .or_insert_with(ValueLog::default)
-                .values
-                .push_back(SequenceValue {
-                    value,
+                .insert(SequenceValue {
+                    value: Arc::new(value),
                     seq: key_and_seq.seq,
                 });
         }
 
-        Ok(Store::new(subjects, SequenceNumber(max_seq)))
+        Ok((MemoryBackend::new(subjects), reserved_sequence, expirations))
+    }
+}
+
+const COMPACTION_MARKER_SUFFIX: &str = "compacted";
+
+/// Storage key of a key's compaction marker. It shares the key's prefix, so it
+/// is removed along with the key, but sorts after every sequence number.
+fn compaction_marker(key: &Key) -> String {
+    format!("{}{}", KeyAndSeq::prefix_str(key), COMPACTION_MARKER_SUFFIX)
+}
+
+fn parse_compaction_marker(storage_key: &str) -> Option<Key> {
+    let prefix = storage_key.strip_suffix(COMPACTION_MARKER_SUFFIX)?;
+    let (_, key) = prefix.split_once('|')?;
+    let key = key.strip_suffix('|')?;
+    Some(Key::new(key.to_string()))
+}
+
+/// A storage operation to be mirrored to Durable Object storage.
+enum StorageOp {
+    Put(KeyAndSeq, Arc<Value>),
+    PutMarker(Key, SequenceNumber),
+    PutReservedSequence(SequenceNumber),
+    PutKeyTtl(Key, Option<u64>),
+    PutExpiry(KeyAndSeq, u64),
+    Delete(Key),
+    DeleteUpTo(Key, SequenceNumber),
+}
+
+/// Storage backend which serves reads from memory and mirrors every mutation
+/// to Durable Object storage.
+///
+/// Durable Object storage is asynchronous, so mutations are queued and written
+/// by a single task, which preserves their order. A failed write can therefore
+/// not reject the change, and panics in the writer task instead.
+pub struct DurableObjectBackend {
+    memory: MemoryBackend,
+    reserved_sequence: SequenceNumber,
+
+    /// The time-to-lives and expiry times loaded from storage, until the
+    /// store takes them.
+    expirations: Expirations,
+
+    ops: UnboundedSender<StorageOp>,
+}
+
+impl DurableObjectBackend {
+    pub fn new(
+        state: WrappedState,
+        memory: MemoryBackend,
+        reserved_sequence: SequenceNumber,
+        expirations: Expirations,
+    ) -> Self {
+        let (ops, receiver) = mpsc::unbounded();
+        wasm_bindgen_futures::spawn_local(write_storage_ops(state, receiver));
+
+        Self {
+            memory,
+            reserved_sequence,
+            expirations,
+            ops,
+        }
+    }
+
+    fn enqueue(&self, op: StorageOp) {
+        self.ops
+            .unbounded_send(op)
+            .expect("Storage writer should outlive the backend.");
+    }
+}
+
+impl StorageBackend for DurableObjectBackend {
+    fn load(&mut self) -> SequenceNumber {
+        self.memory.load().max(self.reserved_sequence)
+    }
+
+    fn reserve_sequence(&mut self, seq: SequenceNumber) -> std::result::Result<(), StorageError> {
+        self.enqueue(StorageOp::PutReservedSequence(seq));
+        self.reserved_sequence = seq;
+        Ok(())
+    }
+
+    fn load_expirations(&mut self) -> Expirations {
+        std::mem::take(&mut self.expirations)
+    }
+
+    fn set_key_ttl(
+        &mut self,
+        key: &Key,
+        ttl: Option<u64>,
+    ) -> std::result::Result<(), StorageError> {
+        self.enqueue(StorageOp::PutKeyTtl(key.clone(), ttl));
+        Ok(())
+    }
+
+    fn expire(&mut self, key: &Key, seq: SequenceNumber, expires_at: u64) {
+        self.enqueue(StorageOp::PutExpiry(
+            KeyAndSeq::new(key.clone(), seq),
+            expires_at,
+        ));
+    }
+
+    fn append(&mut self, key: &Key, value: SequenceValue) {
+        self.enqueue(StorageOp::Put(
+            KeyAndSeq::new(key.clone(), value.seq),
+            value.value.clone(),
+        ));
+        self.memory.append(key, value);
+    }
+
+    fn push_front(&mut self, key: &Key, value: SequenceValue) {
+        self.enqueue(StorageOp::Put(
+            KeyAndSeq::new(key.clone(), value.seq),
+            value.value.clone(),
+        ));
+        self.enqueue(StorageOp::PutMarker(key.clone(), value.seq));
+        self.memory.push_front(key, value);
+    }
+
+    fn set_compacted_through(&mut self, key: &Key, seq: SequenceNumber) {
+        self.enqueue(StorageOp::PutMarker(key.clone(), seq));
+        self.memory.set_compacted_through(key, seq);
+    }
+
+    fn delete(&mut self, key: &Key) {
+        self.enqueue(StorageOp::Delete(key.clone()));
+        self.memory.delete(key);
+    }
+
+    fn delete_up_to(&mut self, key: &Key, seq: SequenceNumber) {
+        // The compaction marker is a watermark, which is kept even once the
+        // compacted value itself has been trimmed.
+        self.enqueue(StorageOp::DeleteUpTo(key.clone(), seq));
+        self.memory.delete_up_to(key, seq);
+    }
+
+    fn get(&self, key: &Key, min_sequence: SequenceNumber) -> Vec<SequenceValue> {
+        self.memory.get(key, min_sequence)
+    }
+
+    fn get_range(&self, key: &Key, range: &ReadRange) -> Vec<SequenceValue> {
+        self.memory.get_range(key, range)
+    }
+
+    fn len(&self, key: &Key) -> usize {
+        self.memory.len(key)
+    }
+
+    fn seq_at(&self, key: &Key, index: usize) -> Option<SequenceNumber> {
+        self.memory.seq_at(key, index)
+    }
+
+    fn compacted_through(&self, key: &Key) -> Option<SequenceNumber> {
+        self.memory.compacted_through(key)
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.memory.keys()
+    }
+}
+
+async fn delete_listed(storage: &mut Storage, list_options: ListOptions<'_>) {
+    let result = storage.list_with_options(list_options).await;
+
+    if let Ok(keys) = result {
+        let keys: Vec<String> = keys
+            .keys()
+            .into_iter()
+            .map(|d| d.unwrap().as_string().unwrap())
+            .collect();
+        storage
+            .delete_multiple(keys)
+            .await
+            .expect("Error deleting keys.");
+    }
+}
+
+async fn write_storage_ops(state: WrappedState, mut receiver: UnboundedReceiver<StorageOp>) {
+    let mut storage = state.state.storage();
+
+    while let Some(op) = receiver.next().await {
+        match op {
+            StorageOp::Put(key_and_seq, value) => {
+                let mut buffer = Vec::new();
+                ciborium::ser::into_writer(value.as_ref(), &mut buffer).unwrap();
+
+                storage
+                    .put(&key_and_seq.to_string(), &buffer)
+                    .await
+                    .expect("Error putting value in storage.");
+            }
+            StorageOp::PutMarker(key, seq) => {
+                storage
+                    .put(&compaction_marker(&key), &cbor_integer(seq.0))
+                    .await
+                    .expect("Error putting compaction marker in storage.");
+            }
+            StorageOp::PutReservedSequence(seq) => {
+                storage
+                    .put(RESERVED_SEQUENCE_KEY, &cbor_integer(seq.0))
+                    .await
+                    .expect("Error putting reserved sequence number in storage.");
+            }
+            StorageOp::PutKeyTtl(key, Some(ttl)) => {
+                storage
+                    .put(&format!("{}{}", KEY_TTL_PREFIX, key), &cbor_integer(ttl))
+                    .await
+                    .expect("Error putting time-to-live in storage.");
+            }
+            StorageOp::PutKeyTtl(key, None) => {
+                storage
+                    .delete(&format!("{}{}", KEY_TTL_PREFIX, key))
+                    .await
+                    .expect("Error deleting time-to-live from storage.");
+            }
+            StorageOp::PutExpiry(key_and_seq, expires_at) => {
+                storage
+                    .put(
+                        &format!("{}{}", key_and_seq, EXPIRY_SUFFIX),
+                        &cbor_integer(expires_at),
+                    )
+                    .await
+                    .expect("Error putting expiry time in storage.");
+            }
+            StorageOp::Delete(key) => {
+                let prefix = KeyAndSeq::prefix_str(&key);
+                delete_listed(&mut storage, ListOptions::new().prefix(&prefix)).await;
+            }
+            StorageOp::DeleteUpTo(key, seq) => {
+                let prefix = KeyAndSeq::prefix_str(&key);
+                let end = KeyAndSeq::new(key, seq.next()).to_string();
+                delete_listed(&mut storage, ListOptions::new().prefix(&prefix).end(&end)).await;
+            }
+        }
     }
 }
 
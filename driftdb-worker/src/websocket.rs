@@ -1,4 +1,4 @@
-use driftdb::MessageFromDatabase;
+use driftdb::{Encoding, Frame, MessageFromDatabase};
 use worker::{Result, WebSocket};
 
 /// A raw WebSocket is not Send or Sync, but that doesn't matter because we are compiling
@@ -31,4 +31,24 @@ impl WrappedWebSocket {
 
         Ok(())
     }
+
+    /// Send a frame, reusing its encoding if another connection has already
+    /// encoded it.
+    pub fn send_frame(&self, frame: &Frame) -> Result<()> {
+        if self.use_cbor {
+            let encoded = frame
+                .encode(Encoding::Cbor)
+                .map_err(|e| worker::Error::RustError(e.to_string()))?;
+            self.socket.send_with_bytes(&encoded)?;
+        } else {
+            let encoded = frame
+                .encode(Encoding::Json)
+                .map_err(|e| worker::Error::RustError(e.to_string()))?;
+            let text = std::str::from_utf8(&encoded)
+                .map_err(|e| worker::Error::RustError(e.to_string()))?;
+            self.socket.send_with_str(text)?;
+        }
+
+        Ok(())
+    }
 }
@@ -0,0 +1,94 @@
+//! Reads and trims of a 100k-entry stream, compared with a linear scan of the
+//! same values, which is how `ValueLog` used to be read.
+
+use ciborium::Value;
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+use driftdb::{
+    types::{SequenceNumber, SequenceValue},
+    ReadRange, ValueLog,
+};
+use std::collections::VecDeque;
+
+const LEN: u64 = 100_000;
+
+fn value_log() -> ValueLog {
+    let mut log = ValueLog::default();
+    for seq in 1..=LEN {
+        log.insert(SequenceValue {
+            value: Value::Integer(seq.into()).into(),
+            seq: SequenceNumber(seq),
+        });
+    }
+    log
+}
+
+fn linear_range(values: &VecDeque<SequenceValue>, range: &ReadRange) -> Vec<SequenceValue> {
+    values
+        .iter()
+        .filter(|v| range.contains(v.seq))
+        .take(range.limit.unwrap_or(usize::MAX))
+        .cloned()
+        .collect()
+}
+
+fn bench_get_range(c: &mut Criterion) {
+    let log = value_log();
+
+    // The most recent 100 values, as read by a client catching up.
+    let tail = ReadRange {
+        after: SequenceNumber(LEN - 100),
+        ..Default::default()
+    };
+    // A page of 100 values from the middle of the stream.
+    let page = ReadRange {
+        after: SequenceNumber(LEN / 2),
+        limit: Some(100),
+        ..Default::default()
+    };
+
+    let mut group = c.benchmark_group("get_range");
+    group.bench_function("tail/binary_search", |b| {
+        b.iter(|| log.get_range(black_box(&tail)))
+    });
+    group.bench_function("tail/linear", |b| {
+        b.iter(|| linear_range(log.values(), black_box(&tail)))
+    });
+    group.bench_function("page/binary_search", |b| {
+        b.iter(|| log.get_range(black_box(&page)))
+    });
+    group.bench_function("page/linear", |b| {
+        b.iter(|| linear_range(log.values(), black_box(&page)))
+    });
+    group.finish();
+}
+
+fn bench_delete_up_to(c: &mut Criterion) {
+    let log = value_log();
+    let seq = SequenceNumber(100);
+
+    let mut group = c.benchmark_group("delete_up_to");
+    group.bench_function("binary_search", |b| {
+        b.iter_batched_ref(
+            || {
+                let mut copy = ValueLog::default();
+                for value in log.values() {
+                    copy.insert(value.clone());
+                }
+                copy
+            },
+            |copy| copy.delete_up_to(black_box(seq)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("linear", |b| {
+        b.iter_batched_ref(
+            || log.values().clone(),
+            |values| values.retain(|v| v.seq > black_box(seq)),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_get_range, bench_delete_up_to);
+criterion_main!(benches);
@@ -0,0 +1,305 @@
+use crate::{
+    store::{ApplyResult, ReadRange, ValueLog},
+    types::{Key, SequenceNumber, SequenceValue},
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
+
+/// A change could not be persisted by a [`StorageBackend`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StorageError(pub String);
+
+impl Display for StorageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not persist change: {}", self.0)
+    }
+}
+
+impl std::error::Error for StorageError {}
+
+/// Time-to-lives and expiry times persisted by a [`StorageBackend`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Expirations {
+    /// Default time-to-live, in milliseconds, of values pushed to each key.
+    pub key_ttls: HashMap<Key, u64>,
+
+    /// Expiry time, in milliseconds since the Unix epoch, of stored values
+    /// which have one.
+    pub values: HashMap<Key, BTreeMap<SequenceNumber, u64>>,
+}
+
+/// Storage for the value logs of a [`Store`](crate::Store).
+///
+/// A `Store` decides which values to keep, and drives the backend through these
+/// primitive operations. Implementations are free to keep values in memory, on
+/// disk, or in a remote key-value store.
+pub trait StorageBackend: Send {
+    /// Load any previously persisted state, returning the highest sequence number
+    /// which may have been handed out: the highest one reserved with
+    /// [`StorageBackend::reserve_sequence`], or the highest one stored,
+    /// whichever is greater.
+    fn load(&mut self) -> SequenceNumber;
+
+    /// Persist that sequence numbers up to and including `seq` may be handed
+    /// out, including to values which are never stored, so that `load` does
+    /// not return a lower one after a restart.
+    fn reserve_sequence(&mut self, _seq: SequenceNumber) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Load the time-to-lives and expiry times persisted with
+    /// [`StorageBackend::set_key_ttl`] and [`StorageBackend::expire`].
+    fn load_expirations(&mut self) -> Expirations {
+        Expirations::default()
+    }
+
+    /// Persist the default time-to-live, in milliseconds, of values pushed to
+    /// `key`, or remove it. It is not removed by [`StorageBackend::delete`].
+    fn set_key_ttl(&mut self, _key: &Key, _ttl: Option<u64>) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    /// Persist that the value of `key` with sequence number `seq` expires at
+    /// `expires_at`, in milliseconds since the Unix epoch. The expiry time is
+    /// removed along with the value.
+    fn expire(&mut self, _key: &Key, _seq: SequenceNumber, _expires_at: u64) {}
+
+    /// Push a value to the end of the stream for `key`.
+    fn append(&mut self, key: &Key, value: SequenceValue);
+
+    /// Push a value to the start of the stream for `key`. This is only used for
+    /// compaction, so the value's sequence number becomes the key's compaction
+    /// watermark (see [`StorageBackend::compacted_through`]).
+    fn push_front(&mut self, key: &Key, value: SequenceValue);
+
+    /// Set the compaction watermark of `key` without pushing a compacted value,
+    /// when restoring a stream whose compacted value has since been trimmed.
+    fn set_compacted_through(&mut self, key: &Key, seq: SequenceNumber);
+
+    /// Delete all values for `key`, along with its compaction watermark, so
+    /// that it is no longer listed by [`StorageBackend::keys`].
+    fn delete(&mut self, key: &Key);
+
+    /// Delete all values for `key` up to and including `seq`.
+    fn delete_up_to(&mut self, key: &Key, seq: SequenceNumber);
+
+    /// Return the values for `key` with a sequence number greater than `min_sequence`.
+    fn get(&self, key: &Key, min_sequence: SequenceNumber) -> Vec<SequenceValue>;
+
+    /// Return the values for `key` within `range`, most recent first if the
+    /// range is reversed.
+    fn get_range(&self, key: &Key, range: &ReadRange) -> Vec<SequenceValue> {
+        let mut values = self.get(key, range.after);
+        values.retain(|v| range.contains(v.seq));
+        if range.reverse {
+            values.reverse();
+        }
+        values.truncate(range.limit.unwrap_or(usize::MAX));
+        values
+    }
+
+    /// The number of values retained for `key`.
+    fn len(&self, key: &Key) -> usize;
+
+    /// The sequence number of the value at position `index` of the stream for `key`.
+    fn seq_at(&self, key: &Key, index: usize) -> Option<SequenceNumber>;
+
+    /// The highest sequence number the stream for `key` has been compacted
+    /// through. It is only reset when the key is deleted.
+    fn compacted_through(&self, key: &Key) -> Option<SequenceNumber>;
+
+    /// All keys which have a stream.
+    fn keys(&self) -> Vec<Key>;
+
+    /// Apply every instruction of an [`ApplyResult`]. Backends which need the
+    /// instructions of one result to be persisted atomically can override this.
+    ///
+    /// If the change can not be persisted, none of its instructions may be
+    /// applied, and the change is not broadcast.
+    fn apply(&mut self, result: &ApplyResult) -> Result<(), StorageError> {
+        result.apply_to(self);
+        Ok(())
+    }
+
+    /// Make sure that all previous writes are durable.
+    fn flush(&mut self) {}
+}
+
+/// A backend which keeps every value log in memory.
+#[derive(Default)]
+pub struct MemoryBackend {
+    subjects: HashMap<Key, ValueLog>,
+}
+
+impl MemoryBackend {
+    pub fn new(subjects: HashMap<Key, ValueLog>) -> Self {
+        Self { subjects }
+    }
+}
+
+impl StorageBackend for MemoryBackend {
+    fn load(&mut self) -> SequenceNumber {
+        self.subjects
+            .values()
+            .filter_map(|log| log.values().back().map(|v| v.seq))
+            .max()
+            .unwrap_or_default()
+    }
+
+    fn append(&mut self, key: &Key, value: SequenceValue) {
+        self.subjects.entry(key.clone()).or_default().insert(value);
+    }
+
+    fn push_front(&mut self, key: &Key, value: SequenceValue) {
+        self.subjects
+            .entry(key.clone())
+            .or_default()
+            .push_front(value);
+    }
+
+    fn set_compacted_through(&mut self, key: &Key, seq: SequenceNumber) {
+        self.subjects
+            .entry(key.clone())
+            .or_default()
+            .compacted_through = Some(seq);
+    }
+
+    fn delete(&mut self, key: &Key) {
+        self.subjects.remove(key);
+    }
+
+    fn delete_up_to(&mut self, key: &Key, seq: SequenceNumber) {
+        if let Some(value_log) = self.subjects.get_mut(key) {
+            value_log.delete_up_to(seq);
+        }
+    }
+
+    fn get(&self, key: &Key, min_sequence: SequenceNumber) -> Vec<SequenceValue> {
+        self.get_range(
+            key,
+            &ReadRange {
+                after: min_sequence,
+                ..Default::default()
+            },
+        )
+    }
+
+    fn get_range(&self, key: &Key, range: &ReadRange) -> Vec<SequenceValue> {
+        self.subjects
+            .get(key)
+            .map(|log| log.get_range(range))
+            .unwrap_or_default()
+    }
+
+    fn len(&self, key: &Key) -> usize {
+        self.subjects
+            .get(key)
+            .map(|v| v.values().len())
+            .unwrap_or(0)
+    }
+
+    fn seq_at(&self, key: &Key, index: usize) -> Option<SequenceNumber> {
+        self.subjects.get(key)?.values().get(index).map(|v| v.seq)
+    }
+
+    fn compacted_through(&self, key: &Key) -> Option<SequenceNumber> {
+        self.subjects.get(key).and_then(|v| v.compacted_through)
+    }
+
+    fn keys(&self) -> Vec<Key> {
+        self.subjects.keys().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{types::Action, Store};
+    use ciborium::Value;
+
+    #[test]
+    fn test_replica_matches_store() {
+        let mut store = Store::default();
+        let mut replica = MemoryBackend::default();
+        let key: Key = "foo".into();
+
+        let actions = [
+            Action::Append,
+            Action::Append,
+            Action::Append,
+            Action::Compact {
+                seq: SequenceNumber(2),
+            },
+            Action::Relay,
+            Action::Append,
+        ];
+
+        for (i, action) in actions.iter().enumerate() {
+            let result = store.apply(&key, Value::Integer(i.into()), action).unwrap();
+            result.apply_to(&mut replica);
+        }
+
+        assert_eq!(
+            store.get(&key, SequenceNumber::default()),
+            replica.get(&key, SequenceNumber::default())
+        );
+        assert_eq!(SequenceNumber(5), replica.load());
+        assert_eq!(Some(SequenceNumber(2)), replica.compacted_through(&key));
+    }
+
+    #[test]
+    fn test_value_log_is_sorted() {
+        let value = |seq: u64| SequenceValue {
+            value: Value::Integer(seq.into()).into(),
+            seq: SequenceNumber(seq),
+        };
+        let seqs = |values: Vec<SequenceValue>| values.iter().map(|v| v.seq.0).collect::<Vec<_>>();
+
+        let mut log = ValueLog::default();
+        for seq in [1, 2, 4, 5, 3] {
+            log.insert(value(seq));
+        }
+        assert_eq!(
+            vec![1, 2, 3, 4, 5],
+            seqs(log.values().iter().cloned().collect())
+        );
+
+        let range = ReadRange {
+            after: SequenceNumber(1),
+            until: Some(SequenceNumber(4)),
+            limit: Some(2),
+            reverse: true,
+        };
+        assert_eq!(vec![4, 3], seqs(log.get_range(&range)));
+
+        log.delete_up_to(SequenceNumber(3));
+        log.push_front(value(3));
+        assert_eq!(vec![3, 4, 5], seqs(log.get_range(&ReadRange::default())));
+        assert_eq!(Some(SequenceNumber(3)), log.compacted_through);
+    }
+
+    #[test]
+    fn test_delete_removes_key() {
+        let mut store = Store::default();
+        let key: Key = "foo".into();
+
+        store
+            .apply(&key, Value::Integer(1.into()), &Action::Append)
+            .unwrap();
+        store
+            .apply(
+                &key,
+                Value::Integer(2.into()),
+                &Action::Compact {
+                    seq: SequenceNumber(1),
+                },
+            )
+            .unwrap();
+        store.apply(&key, Value::Null, &Action::Delete).unwrap();
+
+        assert_eq!(None, store.compacted_through(&key));
+        assert!(store.keys_with_prefix("").is_empty());
+    }
+}
@@ -1,52 +1,202 @@
 use crate::{
     db::DatabaseInner,
-    types::{MessageFromDatabase, MessageToDatabase},
+    error::Error,
+    frame::Frame,
+    store::ReadRange,
+    types::{Key, MessageFromDatabase, MessageToDatabase, Request},
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc, Mutex, Weak,
 };
-use std::sync::{Arc, Mutex, Weak};
 
-type Callback = Arc<Box<dyn Fn(&MessageFromDatabase) + Send + Sync>>;
+type Callback = Arc<Box<dyn Fn(&Frame) + Send + Sync>>;
 
 pub struct Connection {
     pub callback: Callback,
     database: Weak<Mutex<DatabaseInner>>,
+
+    /// Shared with the database, which removes the subscriptions of dropped
+    /// connections when this is non-zero.
+    dropped_connections: Arc<AtomicUsize>,
 }
 
 impl Connection {
     pub fn new<F>(callback: F, database: Arc<Mutex<DatabaseInner>>) -> Connection
     where
-        F: Fn(&MessageFromDatabase) + 'static + Send + Sync,
+        F: Fn(&Frame) + 'static + Send + Sync,
     {
+        let dropped_connections = database.lock().unwrap().dropped_connections();
+
         Connection {
             callback: Arc::new(Box::new(callback)),
             database: Arc::downgrade(&database),
+            dropped_connections,
         }
     }
 
     pub fn send_message(
         self: &Arc<Self>,
         message: &MessageToDatabase,
-    ) -> Result<Option<MessageFromDatabase>, &str> {
-        let db_lock = self.database.upgrade().ok_or("Database is gone")?;
+    ) -> Result<Option<MessageFromDatabase>, Error> {
+        self.send_request_message(message, None)
+    }
+
+    /// Like [`Connection::send_message`], but every `Error` or `Conflict`
+    /// message in the response carries the request's id.
+    pub fn send_request(
+        self: &Arc<Self>,
+        request: &Request,
+    ) -> Result<Option<MessageFromDatabase>, Error> {
+        self.send_request_message(&request.message, request.request_id.as_deref())
+    }
+
+    fn send_request_message(
+        self: &Arc<Self>,
+        message: &MessageToDatabase,
+        request_id: Option<&str>,
+    ) -> Result<Option<MessageFromDatabase>, Error> {
+        validate_keys(message)?;
+
+        let db_lock = self.database.upgrade().ok_or(Error::DatabaseGone)?;
         let mut database = db_lock.lock().unwrap();
+        database.remove_dropped_connections();
 
         let result = match message {
-            MessageToDatabase::Push { key, value, action } => database.push(key, value, action),
-            MessageToDatabase::Get { seq, key } => {
-                database.subscribe(key, Arc::downgrade(self));
-                if let Some(seq) = seq {
-                    // Send prior events on the stream if sequence number is provided.
-                    database.get(key, *seq)
+            MessageToDatabase::Push {
+                key,
+                value,
+                action,
+                ttl,
+            } => database.push(key, value, action, *ttl),
+            MessageToDatabase::Batch { ops, atomic } => database.batch(ops, *atomic),
+            MessageToDatabase::SetTtl { key, ttl } => database.set_ttl(key, *ttl),
+            MessageToDatabase::Get {
+                seq,
+                until_seq,
+                limit,
+                reverse,
+                chunk_size,
+                key,
+                prefix,
+                subscribe,
+                compact_threshold,
+            } => {
+                if *subscribe {
+                    if *prefix {
+                        database.subscribe_prefix(
+                            key.as_str(),
+                            Arc::downgrade(self),
+                            *compact_threshold,
+                        );
+                    } else {
+                        database.subscribe(key, Arc::downgrade(self), *compact_threshold);
+                    }
+                }
+
+                // Send prior events on the stream if sequence number is provided.
+                seq.and_then(|seq| {
+                    let range = ReadRange {
+                        after: seq,
+                        until: *until_seq,
+                        limit: *limit,
+                        reverse: *reverse,
+                    };
+
+                    // Every chunk is queued before the database is unlocked, so
+                    // no push to the key can arrive in between.
+                    let send_chunks = |mut chunks: Vec<MessageFromDatabase>| {
+                        let last = chunks.pop();
+                        database.send_to(self, chunks);
+                        last
+                    };
+
+                    match (*prefix, chunk_size) {
+                        (true, None) => database.get_prefix(key.as_str(), &range),
+                        (true, Some(chunk_size)) => send_chunks(database.get_prefix_chunked(
+                            key.as_str(),
+                            &range,
+                            *chunk_size,
+                        )),
+                        (false, Some(chunk_size)) => {
+                            send_chunks(database.get_chunked(key, &range, *chunk_size))
+                        }
+                        (false, None) => database.get(key, &range),
+                    }
+                })
+            }
+            MessageToDatabase::ListKeys {
+                prefix,
+                cursor,
+                limit,
+            } => database.list_keys(prefix, cursor.as_ref(), *limit),
+            MessageToDatabase::Unsubscribe { key, prefix } => {
+                if *prefix {
+                    database.unsubscribe_prefix(key.as_str(), self);
                 } else {
-                    None
+                    database.unsubscribe(key, self);
                 }
+                None
             }
             MessageToDatabase::Ping { nonce } => Some(MessageFromDatabase::Pong { nonce: *nonce }),
         };
 
+        let result = match (result, request_id) {
+            (Some(mut response), Some(request_id)) => {
+                response.set_request_id(request_id);
+                Some(response)
+            }
+            (result, _) => result,
+        };
+
         if let Some(response) = result.clone() {
-            (self.callback)(&response);
+            database.send_to(self, vec![response]);
         };
 
+        // Deliver messages, including those to other connections, only once
+        // the database is unlocked.
+        let dispatcher = database.dispatcher();
+        drop(database);
+        dispatcher.deliver();
+
         Ok(result)
     }
 }
+
+/// Check the keys which a message writes to.
+fn validate_keys(message: &MessageToDatabase) -> Result<(), Error> {
+    let check = |key: &Key| {
+        if key.as_str().is_empty() {
+            return Err(Error::InvalidKey(
+                key.clone(),
+                "keys may not be empty".to_string(),
+            ));
+        }
+        Ok(())
+    };
+
+    match message {
+        MessageToDatabase::Push { key, .. } | MessageToDatabase::SetTtl { key, .. } => check(key),
+        MessageToDatabase::Batch { ops, .. } => ops.iter().try_for_each(|op| check(&op.key)),
+        _ => Ok(()),
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.dropped_connections.fetch_add(1, Ordering::SeqCst);
+
+        // Clean up right away unless the database is busy, which may be because
+        // this connection is being dropped while the database is locked.
+        if let Some(db_lock) = self.database.upgrade() {
+            if let Ok(mut database) = db_lock.try_lock() {
+                database.remove_dropped_connections();
+
+                // Newly elected compactors may have been sent a request.
+                let dispatcher = database.dispatcher();
+                drop(database);
+                dispatcher.deliver();
+            }
+        }
+    }
+}
@@ -1,67 +1,192 @@
 use crate::{
     connection::Connection,
-    store::{ApplyResult, Store},
-    types::{Action, MessageFromDatabase, SequenceNumber},
+    dispatch::{Dispatcher, FanOut},
+    error::Error,
+    frame::Frame,
+    snapshot::{Snapshot, SnapshotError},
+    store::{ApplyResult, PushInstruction, ReadRange, Store},
+    types::{Action, MessageFromDatabase, PushOp, SequenceNumber},
     Key,
 };
 use ciborium::Value;
 use std::{
     collections::HashMap,
-    sync::{Arc, Mutex, Weak},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex, Weak,
+    },
+    time::{SystemTime, UNIX_EPOCH},
 };
 
+/// Maximum number of keys returned in response to a single `ListKeys` message.
+const MAX_LIST_KEYS: usize = 1000;
+
 type ReplicaCallback = Arc<Box<dyn Fn(&ApplyResult) + Send + Sync>>;
 
+/// Returns the current time in milliseconds since the Unix epoch.
+type Clock = Arc<Box<dyn Fn() -> u64 + Send + Sync>>;
+
+fn system_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn stream_size_message(result: &ApplyResult) -> Option<MessageFromDatabase> {
+    if result.stream_size > 1 {
+        Some(MessageFromDatabase::StreamSize {
+            key: result.key.clone(),
+            size: result.stream_size,
+        })
+    } else {
+        None
+    }
+}
+
+/// Whether a compactor with `threshold` should be asked to compact a stream of
+/// `size` values. A single value can not be compacted any further.
+fn wants_compaction(size: usize, threshold: usize) -> bool {
+    size > threshold.max(1)
+}
+
+/// A connection which volunteered to compact a key, and the stream size past
+/// which it wants to be asked to.
+struct Volunteer {
+    conn: Weak<Connection>,
+    threshold: usize,
+}
+
+/// Add `conn` to `volunteers`, or update its threshold if it is already there.
+fn volunteer(volunteers: &mut Vec<Volunteer>, conn: Weak<Connection>, threshold: usize) {
+    match volunteers.iter_mut().find(|v| v.conn.ptr_eq(&conn)) {
+        Some(existing) => existing.threshold = threshold,
+        None => volunteers.push(Volunteer { conn, threshold }),
+    }
+}
+
 #[derive(Default)]
 pub struct DatabaseInner {
     subscriptions: HashMap<Key, Vec<Weak<Connection>>>,
+    prefix_subscriptions: HashMap<String, Vec<Weak<Connection>>>,
     debug_connections: Vec<Weak<Connection>>,
+    /// Subscribers which volunteered to compact a key, or every key starting
+    /// with a prefix, in the order they volunteered.
+    compactor_volunteers: HashMap<Key, Vec<Volunteer>>,
+    prefix_compactor_volunteers: HashMap<String, Vec<Volunteer>>,
+    /// The volunteer elected to compact each key.
+    compactors: HashMap<Key, Weak<Connection>>,
     replica_callback: Option<ReplicaCallback>,
+    clock: Option<Clock>,
+    dropped_connections: Arc<AtomicUsize>,
+    dispatcher: Arc<Dispatcher>,
     store: Store,
 }
 
 impl DatabaseInner {
+    fn now(&self) -> u64 {
+        match &self.clock {
+            Some(clock) => (clock)(),
+            None => system_time(),
+        }
+    }
+
+    /// The expiry time of a value pushed now with a time-to-live of `ttl`.
+    fn expires_at(&self, ttl: Option<u64>) -> Option<u64> {
+        ttl.map(|ttl| self.now().saturating_add(ttl))
+    }
+
     pub fn push(
         &mut self,
         key: &Key,
-        value: &Value,
+        value: &Arc<Value>,
         action: &Action,
+        ttl: Option<u64>,
     ) -> Option<MessageFromDatabase> {
-        let result = self.store.apply(key, value.clone(), action);
+        let result = match self.apply_push(key, value, action, ttl) {
+            Ok(result) => result,
+            Err(conflict) => return Some(conflict),
+        };
 
-        if !self.debug_connections.is_empty() {
-            if result.mutates() {
-                let data = self.store.get(key, SequenceNumber::default());
+        let mut fan_out = FanOut::default();
+        self.plan_broadcast(&result, &mut fan_out);
+        self.dispatcher.enqueue(fan_out);
 
-                let message = MessageFromDatabase::Init {
-                    data,
-                    key: key.clone(),
-                };
+        stream_size_message(&result)
+    }
 
-                self.debug_connections.retain(|conn| {
-                    if let Some(conn) = conn.upgrade() {
-                        (conn.callback)(&message);
-                        true
-                    } else {
-                        false
-                    }
+    /// Apply a batch of pushes. Subscribers receive the messages of the whole
+    /// batch at once, wrapped in a single `Batch` message.
+    ///
+    /// The precondition of each push is checked against the state left by the
+    /// pushes before it. If `atomic` is set, the whole batch is tried out on a
+    /// scratch copy of its streams first, and if any precondition fails, no
+    /// push is applied. A push which can not be persisted also stops an atomic
+    /// batch, but the pushes before it stay applied. Otherwise, pushes which
+    /// fail are skipped.
+    pub fn batch(&mut self, ops: &[PushOp], atomic: bool) -> Option<MessageFromDatabase> {
+        let mut responses = Vec::new();
+
+        if atomic {
+            let mut scratch = self.store.scratch(ops.iter().map(|op| &op.key));
+            for op in ops {
+                let expires_at = self.expires_at(op.ttl.or_else(|| scratch.key_ttl(&op.key)));
+                let result =
+                    scratch.apply_with_expiry(&op.key, op.value.clone(), &op.action, expires_at);
+                if let Err(err) = result {
+                    responses.push(err.to_message(None));
+                }
+            }
+
+            if !responses.is_empty() {
+                return Some(MessageFromDatabase::Batch {
+                    messages: responses,
                 });
-            } else if let Some(seq_value) = &result.broadcast {
-                let message = MessageFromDatabase::Push {
-                    key: key.clone(),
-                    value: seq_value.value.clone(),
-                    seq: seq_value.seq,
-                };
-                self.debug_connections.retain(|conn| {
-                    if let Some(conn) = conn.upgrade() {
-                        (conn.callback)(&message);
-                        true
-                    } else {
-                        false
+            }
+        }
+
+        let mut fan_out = FanOut::grouped();
+        for op in ops {
+            match self.apply_push(&op.key, &op.value, &op.action, op.ttl) {
+                Ok(result) => {
+                    self.plan_broadcast(&result, &mut fan_out);
+                    responses.extend(stream_size_message(&result));
+                }
+                Err(err) => {
+                    responses.push(err);
+                    // Preconditions of an atomic batch have already passed, so
+                    // this could only be a write which was not persisted.
+                    if atomic {
+                        break;
                     }
-                });
+                }
             }
         }
+        self.dispatcher.enqueue(fan_out);
+
+        if responses.is_empty() {
+            None
+        } else {
+            Some(MessageFromDatabase::Batch {
+                messages: responses,
+            })
+        }
+    }
+
+    /// Apply a push to the store, returning an `Error` message instead if its
+    /// precondition fails or it can not be persisted.
+    fn apply_push(
+        &mut self,
+        key: &Key,
+        value: &Arc<Value>,
+        action: &Action,
+        ttl: Option<u64>,
+    ) -> Result<ApplyResult, MessageFromDatabase> {
+        let expires_at = self.expires_at(ttl.or_else(|| self.store.key_ttl(key)));
+        let result = self
+            .store
+            .apply_with_expiry(key, value.clone(), action, expires_at)
+            .map_err(|err| err.to_message(None))?;
 
         if result.mutates() {
             if let Some(replica_callback) = &self.replica_callback {
@@ -69,17 +194,48 @@ impl DatabaseInner {
             }
         }
 
-        if let Some(seq_value) = result.broadcast {
-            let message = MessageFromDatabase::Push {
+        Ok(result)
+    }
+
+    /// Add the messages which broadcast `result` to debug connections and
+    /// subscribers to `fan_out`.
+    fn plan_broadcast(&mut self, result: &ApplyResult, fan_out: &mut FanOut) {
+        let key = &result.key;
+        let compacted = result.compaction().map(|seq| {
+            Arc::new(Frame::new(MessageFromDatabase::Compacted {
                 key: key.clone(),
-                value: seq_value.value.clone(),
-                seq: seq_value.seq,
+                seq,
+            }))
+        });
+
+        if !self.debug_connections.is_empty() {
+            let message = if result.mutates() {
+                Some(MessageFromDatabase::Init {
+                    data: self.store.get(key, SequenceNumber::default()),
+                    key: key.clone(),
+                    compacted_through: self.store.compacted_through(key),
+                    cursor: None,
+                })
+            } else {
+                result
+                    .broadcast
+                    .as_ref()
+                    .map(|seq_value| MessageFromDatabase::Push {
+                        key: key.clone(),
+                        value: seq_value.value.clone(),
+                        seq: seq_value.seq,
+                        relay: true,
+                    })
             };
 
-            if let Some(listeners) = self.subscriptions.get_mut(key) {
-                listeners.retain(|conn| {
+            if let Some(message) = message {
+                let frame = Arc::new(Frame::new(message));
+                self.debug_connections.retain(|conn| {
                     if let Some(conn) = conn.upgrade() {
-                        (conn.callback)(&message);
+                        fan_out.add(conn.clone(), frame.clone());
+                        if let Some(compacted) = &compacted {
+                            fan_out.add(conn, compacted.clone());
+                        }
                         true
                     } else {
                         false
@@ -88,28 +244,472 @@ impl DatabaseInner {
             }
         }
 
-        if result.stream_size > 1 {
-            let message = MessageFromDatabase::StreamSize {
+        let frame = if let Some(seq_value) = &result.broadcast {
+            Some(Arc::new(Frame::new(MessageFromDatabase::Push {
                 key: key.clone(),
-                size: result.stream_size,
-            };
-            return Some(message);
+                value: seq_value.value.clone(),
+                seq: seq_value.seq,
+                relay: result.push_instruction.is_none(),
+            })))
+        } else if let Some(seq) = result.tombstone {
+            Some(Arc::new(Frame::new(MessageFromDatabase::Deleted {
+                key: key.clone(),
+                seq,
+            })))
+        } else {
+            compacted
+        };
+
+        let subscribers = self.subscribers(key);
+        if let Some(frame) = frame {
+            for conn in &subscribers {
+                fan_out.add(conn.clone(), frame.clone());
+            }
         }
 
-        None
+        if matches!(result.push_instruction, Some(PushInstruction::Push(_))) {
+            if let Some((compactor, threshold)) = self.elect_compactor(key) {
+                if wants_compaction(result.stream_size, threshold) {
+                    let request = MessageFromDatabase::CompactionRequested {
+                        key: key.clone(),
+                        size: result.stream_size,
+                    };
+                    fan_out.add(compactor, Arc::new(Frame::new(request)));
+                }
+            }
+        }
+    }
+
+    /// The connection which should compact `key`, out of those which
+    /// volunteered to, and its threshold. The compactor stays the same for as
+    /// long as it volunteers; otherwise, the earliest volunteer is elected.
+    fn elect_compactor(&mut self, key: &Key) -> Option<(Arc<Connection>, usize)> {
+        let volunteers = self.volunteers(key);
+        if let Some(current) = self.compactors.get(key).and_then(Weak::upgrade) {
+            if let Some(elected) = volunteers
+                .iter()
+                .find(|(conn, _)| Arc::ptr_eq(conn, &current))
+            {
+                return Some(elected.clone());
+            }
+        }
+
+        match volunteers.into_iter().next() {
+            Some((conn, threshold)) => {
+                self.compactors.insert(key.clone(), Arc::downgrade(&conn));
+                Some((conn, threshold))
+            }
+            None => {
+                self.compactors.remove(key);
+                None
+            }
+        }
+    }
+
+    /// Elect a new compactor for `key` after the current one unsubscribed or
+    /// was dropped. The new compactor is asked to compact the key right away,
+    /// since a request sent to the old one may not have been acted on.
+    fn reelect_compactor(&mut self, key: &Key) {
+        let Some((compactor, threshold)) = self.elect_compactor(key) else {
+            return;
+        };
+
+        let size = self.store.stream_size(key);
+        if wants_compaction(size, threshold) {
+            self.send_to(
+                &compactor,
+                vec![MessageFromDatabase::CompactionRequested {
+                    key: key.clone(),
+                    size,
+                }],
+            );
+        }
+    }
+
+    /// Keys whose compactor is `connection`.
+    fn compacted_by(&self, connection: &Connection) -> Vec<Key> {
+        self.compactors
+            .iter()
+            .filter(|(_, conn)| std::ptr::eq(conn.as_ptr(), connection))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Every live connection subscribed to `key`, either directly or through
+    /// a prefix, without duplicates.
+    fn subscribers(&mut self, key: &Key) -> Vec<Arc<Connection>> {
+        let mut subscribers: Vec<Arc<Connection>> = Vec::new();
+        let mut add_live = |listeners: &mut Vec<Weak<Connection>>| {
+            listeners.retain(|conn| {
+                if let Some(conn) = conn.upgrade() {
+                    if !subscribers.iter().any(|c| Arc::ptr_eq(c, &conn)) {
+                        subscribers.push(conn);
+                    }
+                    true
+                } else {
+                    false
+                }
+            });
+        };
+
+        if let Some(listeners) = self.subscriptions.get_mut(key) {
+            add_live(listeners);
+        }
+
+        for (prefix, listeners) in self.prefix_subscriptions.iter_mut() {
+            if key.as_str().starts_with(prefix.as_str()) {
+                add_live(listeners);
+            }
+        }
+
+        subscribers
+    }
+
+    /// Every live connection which volunteered to compact `key`, either
+    /// directly or through a prefix, with its threshold and without duplicates.
+    fn volunteers(&mut self, key: &Key) -> Vec<(Arc<Connection>, usize)> {
+        let mut volunteers: Vec<(Arc<Connection>, usize)> = Vec::new();
+        let mut add_live = |listeners: &mut Vec<Volunteer>| {
+            listeners.retain(|volunteer| {
+                if let Some(conn) = volunteer.conn.upgrade() {
+                    if !volunteers.iter().any(|(c, _)| Arc::ptr_eq(c, &conn)) {
+                        volunteers.push((conn, volunteer.threshold));
+                    }
+                    true
+                } else {
+                    false
+                }
+            });
+        };
+
+        if let Some(listeners) = self.compactor_volunteers.get_mut(key) {
+            add_live(listeners);
+        }
+
+        for (prefix, listeners) in self.prefix_compactor_volunteers.iter_mut() {
+            if key.as_str().starts_with(prefix.as_str()) {
+                add_live(listeners);
+            }
+        }
+
+        volunteers
+    }
+
+    pub fn set_ttl(&mut self, key: &Key, ttl: Option<u64>) -> Option<MessageFromDatabase> {
+        let result = self.store.set_key_ttl(key, ttl);
+        result.err().map(|err| Error::from(err).to_message(None))
+    }
+
+    /// Remove expired values. Subscribers of every affected key receive a
+    /// fresh `Init` message.
+    fn purge_expired(&mut self) {
+        let now = self.now();
+        let results = self.store.purge_expired(now);
+
+        for result in results {
+            if let Some(replica_callback) = &self.replica_callback {
+                (replica_callback)(&result);
+            }
+
+            self.reset_key(&result.key);
+        }
+    }
+
+    /// Send the full, current stream for `key` to its subscribers and to debug
+    /// connections, after it has been replaced wholesale.
+    fn reset_key(&mut self, key: &Key) {
+        let frame = Arc::new(Frame::new(MessageFromDatabase::Init {
+            data: self.store.get(key, SequenceNumber::default()),
+            key: key.clone(),
+            compacted_through: self.store.compacted_through(key),
+            cursor: None,
+        }));
+
+        let mut fan_out = FanOut::default();
+        for conn in self.subscribers(key) {
+            fan_out.add(conn, frame.clone());
+        }
+        for conn in &self.debug_connections {
+            if let Some(conn) = conn.upgrade() {
+                fan_out.add(conn, frame.clone());
+            }
+        }
+        self.dispatcher.enqueue(fan_out);
     }
 
-    pub fn subscribe(&mut self, key: &Key, connection: Weak<Connection>) {
+    /// Subscribe to `key`. If `compact_threshold` is set, the connection also
+    /// volunteers to compact the key. Subscribing a connection which is
+    /// already subscribed has no effect, except to volunteer it.
+    pub fn subscribe(
+        &mut self,
+        key: &Key,
+        connection: Weak<Connection>,
+        compact_threshold: Option<usize>,
+    ) {
+        if let Some(threshold) = compact_threshold {
+            let volunteers = self.compactor_volunteers.entry(key.clone()).or_default();
+            volunteer(volunteers, connection.clone(), threshold);
+        }
+
         let listeners = self.subscriptions.entry(key.clone()).or_default();
-        listeners.push(connection);
+        if !listeners.iter().any(|conn| conn.ptr_eq(&connection)) {
+            listeners.push(connection);
+        }
+    }
+
+    /// Subscribe to every key which starts with `prefix`, and if
+    /// `compact_threshold` is set, volunteer to compact them.
+    pub fn subscribe_prefix(
+        &mut self,
+        prefix: &str,
+        connection: Weak<Connection>,
+        compact_threshold: Option<usize>,
+    ) {
+        if let Some(threshold) = compact_threshold {
+            let volunteers = self
+                .prefix_compactor_volunteers
+                .entry(prefix.to_string())
+                .or_default();
+            volunteer(volunteers, connection.clone(), threshold);
+        }
+
+        let listeners = self
+            .prefix_subscriptions
+            .entry(prefix.to_string())
+            .or_default();
+        if !listeners.iter().any(|conn| conn.ptr_eq(&connection)) {
+            listeners.push(connection);
+        }
+    }
+
+    pub fn unsubscribe(&mut self, key: &Key, connection: &Connection) {
+        if let Some(listeners) = self.subscriptions.get_mut(key) {
+            listeners.retain(|conn| !std::ptr::eq(conn.as_ptr(), connection));
+            if listeners.is_empty() {
+                self.subscriptions.remove(key);
+            }
+        }
+        if let Some(volunteers) = self.compactor_volunteers.get_mut(key) {
+            volunteers.retain(|v| !std::ptr::eq(v.conn.as_ptr(), connection));
+            if volunteers.is_empty() {
+                self.compactor_volunteers.remove(key);
+            }
+        }
+
+        let is_compactor = self
+            .compactors
+            .get(key)
+            .is_some_and(|conn| std::ptr::eq(conn.as_ptr(), connection));
+        if is_compactor {
+            self.reelect_compactor(key);
+        }
+    }
+
+    pub fn unsubscribe_prefix(&mut self, prefix: &str, connection: &Connection) {
+        if let Some(listeners) = self.prefix_subscriptions.get_mut(prefix) {
+            listeners.retain(|conn| !std::ptr::eq(conn.as_ptr(), connection));
+            if listeners.is_empty() {
+                self.prefix_subscriptions.remove(prefix);
+            }
+        }
+        if let Some(volunteers) = self.prefix_compactor_volunteers.get_mut(prefix) {
+            volunteers.retain(|v| !std::ptr::eq(v.conn.as_ptr(), connection));
+            if volunteers.is_empty() {
+                self.prefix_compactor_volunteers.remove(prefix);
+            }
+        }
+
+        for key in self.compacted_by(connection) {
+            if key.as_str().starts_with(prefix) {
+                self.reelect_compactor(&key);
+            }
+        }
+    }
+
+    /// Queue messages for a single connection, after any already planned.
+    pub fn send_to(&self, conn: &Arc<Connection>, messages: Vec<MessageFromDatabase>) {
+        let mut fan_out = FanOut::default();
+        for message in messages {
+            fan_out.add(conn.clone(), Arc::new(Frame::new(message)));
+        }
+        self.dispatcher.enqueue(fan_out);
+    }
+
+    /// Delivers planned messages. Call [`Dispatcher::deliver`] on it after
+    /// unlocking the database.
+    pub fn dispatcher(&self) -> Arc<Dispatcher> {
+        self.dispatcher.clone()
+    }
+
+    /// A counter which connections increment when they are dropped.
+    pub fn dropped_connections(&self) -> Arc<AtomicUsize> {
+        self.dropped_connections.clone()
+    }
+
+    /// Remove the subscriptions of every connection which has been dropped, and
+    /// replace any compactors among them.
+    pub fn remove_dropped_connections(&mut self) {
+        if self.dropped_connections.swap(0, Ordering::SeqCst) == 0 {
+            return;
+        }
+
+        let is_live = |conn: &Weak<Connection>| conn.strong_count() > 0;
+        self.subscriptions.retain(|_, listeners| {
+            listeners.retain(is_live);
+            !listeners.is_empty()
+        });
+        self.prefix_subscriptions.retain(|_, listeners| {
+            listeners.retain(is_live);
+            !listeners.is_empty()
+        });
+        self.debug_connections.retain(is_live);
+        self.compactor_volunteers.retain(|_, volunteers| {
+            volunteers.retain(|v| is_live(&v.conn));
+            !volunteers.is_empty()
+        });
+        self.prefix_compactor_volunteers.retain(|_, volunteers| {
+            volunteers.retain(|v| is_live(&v.conn));
+            !volunteers.is_empty()
+        });
+
+        let orphaned: Vec<Key> = self
+            .compactors
+            .iter()
+            .filter(|(_, conn)| !is_live(conn))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in orphaned {
+            self.reelect_compactor(&key);
+        }
+    }
+
+    /// Return an `Init` message for every existing key which starts with
+    /// `prefix`, wrapped in a `Batch` message.
+    pub fn get_prefix(&self, prefix: &str, range: &ReadRange) -> Option<MessageFromDatabase> {
+        let messages = self
+            .store
+            .keys_with_prefix(prefix)
+            .into_iter()
+            .filter_map(|key| self.get(&key, range))
+            .collect();
+
+        Some(MessageFromDatabase::Batch { messages })
+    }
+
+    /// Like [`DatabaseInner::get`], but split into `InitChunk` messages of at
+    /// most `chunk_size` values if there are more than that. Each chunk is
+    /// read from the store separately.
+    pub fn get_chunked(
+        &self,
+        key: &Key,
+        range: &ReadRange,
+        chunk_size: usize,
+    ) -> Vec<MessageFromDatabase> {
+        let chunk_size = chunk_size.max(1);
+        let compacted_through = self.store.compacted_through(key);
+        let mut range = range.clone();
+        let mut messages = Vec::new();
+
+        loop {
+            let limit = range
+                .limit
+                .map_or(chunk_size, |limit| limit.min(chunk_size));
+            let (data, cursor) = self.store.get_range(
+                key,
+                &ReadRange {
+                    limit: Some(limit),
+                    ..range.clone()
+                },
+            );
+            let remaining = range.limit.map(|limit| limit - data.len());
+
+            let next = match cursor {
+                Some(next) if remaining != Some(0) => next,
+                // Either no values remain, or the requested number of values has
+                // been read, and the cursor is where the client can continue.
+                cursor if messages.is_empty() => {
+                    return vec![MessageFromDatabase::Init {
+                        key: key.clone(),
+                        data,
+                        compacted_through,
+                        cursor,
+                    }];
+                }
+                cursor => {
+                    messages.push(MessageFromDatabase::InitChunk {
+                        key: key.clone(),
+                        data,
+                        compacted_through: None,
+                        last: true,
+                        cursor,
+                    });
+                    return messages;
+                }
+            };
+
+            messages.push(MessageFromDatabase::InitChunk {
+                key: key.clone(),
+                data,
+                compacted_through: if messages.is_empty() {
+                    compacted_through
+                } else {
+                    None
+                },
+                last: false,
+                cursor: None,
+            });
+
+            range.limit = remaining;
+            if range.reverse {
+                range.until = Some(next);
+            } else {
+                range.after = next;
+            }
+        }
+    }
+
+    /// Like [`DatabaseInner::get_prefix`], but every existing key's values are
+    /// sent as separate messages, split as by [`DatabaseInner::get_chunked`],
+    /// instead of in one `Batch`. If no key matches, returns an empty `Batch`.
+    pub fn get_prefix_chunked(
+        &self,
+        prefix: &str,
+        range: &ReadRange,
+        chunk_size: usize,
+    ) -> Vec<MessageFromDatabase> {
+        let messages: Vec<MessageFromDatabase> = self
+            .store
+            .keys_with_prefix(prefix)
+            .iter()
+            .flat_map(|key| self.get_chunked(key, range, chunk_size))
+            .collect();
+
+        if messages.is_empty() {
+            return vec![MessageFromDatabase::Batch { messages }];
+        }
+        messages
+    }
+
+    pub fn list_keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&Key>,
+        limit: Option<usize>,
+    ) -> Option<MessageFromDatabase> {
+        let limit = limit.unwrap_or(MAX_LIST_KEYS).min(MAX_LIST_KEYS);
+        let (keys, next_cursor) = self.store.list_keys(prefix, cursor, limit);
+
+        Some(MessageFromDatabase::Keys { keys, next_cursor })
     }
 
-    pub fn get(&self, key: &Key, seq: SequenceNumber) -> Option<MessageFromDatabase> {
-        let data = self.store.get(key, seq);
+    pub fn get(&self, key: &Key, range: &ReadRange) -> Option<MessageFromDatabase> {
+        let (data, cursor) = self.store.get_range(key, range);
 
         Some(MessageFromDatabase::Init {
             data,
             key: key.clone(),
+            compacted_through: self.store.compacted_through(key),
+            cursor,
         })
     }
 }
@@ -140,9 +740,88 @@ impl Database {
         self.inner.lock().unwrap().replica_callback = Some(Arc::new(Box::new(callback)));
     }
 
+    /// Set the clock used to compute when values expire. Defaults to the
+    /// system clock.
+    pub fn set_clock<F>(&mut self, clock: F)
+    where
+        F: Fn() -> u64 + 'static + Send + Sync,
+    {
+        self.inner.lock().unwrap().clock = Some(Arc::new(Box::new(clock)));
+    }
+
+    /// Remove every value whose time-to-live has passed, and return the time
+    /// at which the next value expires, in milliseconds since the Unix epoch.
+    pub fn purge_expired(&self) -> Option<u64> {
+        let mut db = self.inner.lock().unwrap();
+        db.remove_dropped_connections();
+        db.purge_expired();
+        let next_expiry = db.store.next_expiry();
+        let dispatcher = db.dispatcher();
+        drop(db);
+
+        dispatcher.deliver();
+        next_expiry
+    }
+
+    /// The time at which the next value expires, in milliseconds since the
+    /// Unix epoch.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.inner.lock().unwrap().store.next_expiry()
+    }
+
+    /// Make sure that all writes to the underlying storage are durable.
+    pub fn flush(&self) {
+        self.inner.lock().unwrap().store.flush();
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        self.inner.lock().unwrap().store.snapshot()
+    }
+
+    /// Replace the contents of the database with a snapshot. Subscribers of
+    /// every affected key receive a fresh `Init` message, even if the storage
+    /// failed part-way and the snapshot was only partially restored. An
+    /// invalid snapshot is rejected without changing anything.
+    pub fn restore(&self, snapshot: Snapshot) -> Result<(), SnapshotError> {
+        snapshot.validate()?;
+
+        let mut db = self.inner.lock().unwrap();
+        let mut keys = db.store.keys_with_prefix("");
+        keys.extend(snapshot.keys.iter().map(|key| key.key.clone()));
+
+        let results = db.store.restore(snapshot);
+
+        if let (Ok(results), Some(replica_callback)) = (&results, &db.replica_callback) {
+            for result in results {
+                (replica_callback)(result);
+            }
+        }
+
+        keys.sort();
+        keys.dedup();
+        for key in keys {
+            db.reset_key(&key);
+        }
+        let dispatcher = db.dispatcher();
+        drop(db);
+
+        dispatcher.deliver();
+        results.map(|_| ())
+    }
+
     pub fn connect<F>(&self, callback: F) -> Arc<Connection>
     where
         F: Fn(&MessageFromDatabase) + 'static + Send + Sync,
+    {
+        self.connect_frames(move |frame: &Frame| callback(frame.message()))
+    }
+
+    /// Like [`Database::connect`], but the callback receives [`Frame`]s, whose
+    /// encodings are shared with every other connection receiving the same
+    /// message.
+    pub fn connect_frames<F>(&self, callback: F) -> Arc<Connection>
+    where
+        F: Fn(&Frame) + 'static + Send + Sync,
     {
         Arc::new(Connection::new(callback, self.inner.clone()))
     }
@@ -150,17 +829,37 @@ impl Database {
     pub fn connect_debug<F>(&self, callback: F) -> Arc<Connection>
     where
         F: Fn(&MessageFromDatabase) + 'static + Send + Sync,
+    {
+        self.connect_debug_frames(move |frame: &Frame| callback(frame.message()))
+    }
+
+    /// Like [`Database::connect_debug`], but the callback receives [`Frame`]s.
+    pub fn connect_debug_frames<F>(&self, callback: F) -> Arc<Connection>
+    where
+        F: Fn(&Frame) + 'static + Send + Sync,
     {
         let conn = Arc::new(Connection::new(callback, self.inner.clone()));
 
         let mut db = self.inner.lock().unwrap();
 
-        for (key, values) in db.store.dump() {
-            let message = MessageFromDatabase::Init { data: values, key };
-            (conn.callback)(&message);
-        }
+        let messages = db
+            .store
+            .dump()
+            .into_iter()
+            .map(|(key, values)| MessageFromDatabase::Init {
+                data: values,
+                compacted_through: db.store.compacted_through(&key),
+                key,
+                cursor: None,
+            })
+            .collect();
+        db.send_to(&conn, messages);
 
         db.debug_connections.push(Arc::downgrade(&conn));
+        let dispatcher = db.dispatcher();
+        drop(db);
+
+        dispatcher.deliver();
         conn
     }
 }
@@ -169,20 +868,37 @@ impl Database {
 mod tests {
     use super::*;
     use crate::{
+        error::Error,
+        frame::Encoding,
+        store::DeleteInstruction,
         tests::MessageStash,
-        types::{Action, SequenceNumber, SequenceValue},
-        MessageToDatabase,
+        types::{Action, KeyInfo, PushOp, SequenceNumber, SequenceValue},
+        MemoryBackend, MessageToDatabase, StorageBackend, StorageError,
     };
     use serde_json::json;
+    use std::sync::atomic::AtomicU64;
 
-    fn json_to_cbor(value: serde_json::Value) -> ciborium::value::Value {
-        ciborium::Value::serialized(&value).unwrap()
+    fn json_to_cbor(value: serde_json::Value) -> Arc<ciborium::value::Value> {
+        Arc::new(ciborium::Value::serialized(&value).unwrap())
     }
 
     fn subscribe(conn: &Arc<Connection>, key: &str) {
+        subscribe_compactor(conn, key, None);
+    }
+
+    /// Subscribe to `key`, and volunteer to compact it if `compact_threshold`
+    /// is set.
+    fn subscribe_compactor(conn: &Arc<Connection>, key: &str, compact_threshold: Option<usize>) {
         conn.send_message(&MessageToDatabase::Get {
             seq: Some(SequenceNumber::default()),
+            until_seq: None,
+            limit: None,
+            reverse: false,
+            chunk_size: None,
             key: key.into(),
+            prefix: false,
+            subscribe: true,
+            compact_threshold,
         })
         .unwrap();
     }
@@ -192,6 +908,7 @@ mod tests {
             key: key.into(),
             value: json_to_cbor(value),
             action,
+            ttl: None,
         })
         .unwrap();
     }
@@ -208,7 +925,9 @@ mod tests {
         assert_eq!(
             Some(MessageFromDatabase::Init {
                 data: vec![],
-                key: "foo".into()
+                key: "foo".into(),
+                cursor: None,
+                compacted_through: None,
             }),
             stash.next()
         );
@@ -236,6 +955,8 @@ mod tests {
             Some(MessageFromDatabase::Init {
                 key: "foo".into(),
                 data: vec![],
+                cursor: None,
+                compacted_through: None,
             }),
             stash.next()
         );
@@ -244,6 +965,7 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "bar": "baz" })),
                 seq: SequenceNumber(2),
+                relay: true,
             }),
             stash.next()
         );
@@ -262,7 +984,9 @@ mod tests {
         assert_eq!(
             Some(MessageFromDatabase::Init {
                 data: vec![],
-                key: "foo".into()
+                key: "foo".into(),
+                cursor: None,
+                compacted_through: None,
             }),
             stash.next()
         );
@@ -274,6 +998,7 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "bar": "baz" })),
                 seq: SequenceNumber(1),
+                relay: true,
             }),
             stash.next()
         );
@@ -286,6 +1011,7 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "abc": "def" })),
                 seq: SequenceNumber(2),
+                relay: true,
             }),
             stash.next()
         );
@@ -301,7 +1027,9 @@ mod tests {
         assert_eq!(
             Some(MessageFromDatabase::Init {
                 data: vec![],
-                key: "foo".into()
+                key: "foo".into(),
+                cursor: None,
+                compacted_through: None,
             }),
             stash1.next()
         );
@@ -312,7 +1040,9 @@ mod tests {
         assert_eq!(
             Some(MessageFromDatabase::Init {
                 data: vec![],
-                key: "foo".into()
+                key: "foo".into(),
+                cursor: None,
+                compacted_through: None,
             }),
             stash2.next()
         );
@@ -324,6 +1054,7 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "bar": "baz" })),
                 seq: SequenceNumber(1),
+                relay: true,
             }),
             stash1.next()
         );
@@ -333,6 +1064,7 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "bar": "baz" })),
                 seq: SequenceNumber(1),
+                relay: true,
             }),
             stash2.next()
         );
@@ -350,7 +1082,9 @@ mod tests {
         assert_eq!(
             Some(MessageFromDatabase::Init {
                 data: vec![],
-                key: "foo".into()
+                key: "foo".into(),
+                cursor: None,
+                compacted_through: None,
             }),
             stash.next()
         );
@@ -362,6 +1096,7 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "bar": "baz" })),
                 seq: SequenceNumber(1),
+                relay: false,
             }),
             stash.next()
         );
@@ -378,7 +1113,9 @@ mod tests {
                     value: json_to_cbor(json!({ "bar": "baz" })),
                     seq: SequenceNumber(1),
                 }],
-                key: "foo".into()
+                key: "foo".into(),
+                cursor: None,
+                compacted_through: None,
             }),
             stash2.next()
         );
@@ -394,7 +1131,9 @@ mod tests {
         assert_eq!(
             Some(MessageFromDatabase::Init {
                 data: vec![],
-                key: "foo".into()
+                key: "foo".into(),
+                cursor: None,
+                compacted_through: None,
             }),
             stash1.next()
         );
@@ -408,6 +1147,7 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "bar": "baz" })),
                 seq: SequenceNumber(1),
+                relay: true,
             }),
             stash1.next()
         );
@@ -423,12 +1163,14 @@ mod tests {
         let (stash, callback) = MessageStash::new();
         let conn = db.connect(callback);
 
-        subscribe(&conn, "foo");
+        subscribe_compactor(&conn, "foo", Some(1));
 
         assert_eq!(
             Some(MessageFromDatabase::Init {
                 data: vec![],
-                key: "foo".into()
+                key: "foo".into(),
+                cursor: None,
+                compacted_through: None,
             }),
             stash.next()
         );
@@ -440,6 +1182,7 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "bar": "baz" })),
                 seq: SequenceNumber(1),
+                relay: false,
             }),
             stash.next()
         );
@@ -451,6 +1194,15 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "abc": "def" })),
                 seq: SequenceNumber(2),
+                relay: false,
+            }),
+            stash.next()
+        );
+        // As the only volunteer, the sender is also the compactor.
+        assert_eq!(
+            Some(MessageFromDatabase::CompactionRequested {
+                key: "foo".into(),
+                size: 2,
             }),
             stash.next()
         );
@@ -469,6 +1221,14 @@ mod tests {
                 key: "foo".into(),
                 value: json_to_cbor(json!({ "boo": "baa" })),
                 seq: SequenceNumber(3),
+                relay: false,
+            }),
+            stash.next()
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::CompactionRequested {
+                key: "foo".into(),
+                size: 3,
             }),
             stash.next()
         );
@@ -502,44 +1262,1096 @@ mod tests {
                         value: json_to_cbor(json!({ "boo": "baa" })),
                         seq: SequenceNumber(3),
                     }
-                ]
+                ],
+                cursor: None,
+                compacted_through: None,
             }),
             stash2.next()
         );
     }
 
     #[test]
-    fn test_compact() {
+    fn test_snapshot_restore() {
         let db = Database::new();
-
-        let (stash, callback) = MessageStash::new();
-        let conn = db.connect(callback);
-
-        subscribe(&conn, "foo");
-
-        assert_eq!(
-            Some(MessageFromDatabase::Init {
-                data: vec![],
-                key: "foo".into()
-            }),
-            stash.next()
-        );
+        let conn = db.connect(|_| ());
 
         push(&conn, "foo", json!({ "bar": "baz" }), Action::Append);
         push(&conn, "foo", json!({ "abc": "def" }), Action::Append);
-        push(&conn, "foo", json!({ "boo": "baa" }), Action::Append);
         push(
             &conn,
             "foo",
             json!({ "moo": "ram" }),
             Action::Compact {
-                seq: SequenceNumber(2),
+                seq: SequenceNumber(1),
             },
         );
+        push(&conn, "bar", json!(1), Action::Replace);
+        push(&conn, "bar", json!(2), Action::Relay);
 
-        // The durable message should be sent to new subscriptions.
-        let (stash2, callback2) = MessageStash::new();
-        let conn2 = db.connect(callback2);
+        // The compacted value of "qux" is trimmed, but its watermark is kept.
+        push(&conn, "qux", json!(1), Action::Append);
+        push(&conn, "qux", json!(2), Action::Append);
+        push(
+            &conn,
+            "qux",
+            json!(12),
+            Action::Compact {
+                seq: SequenceNumber(5),
+            },
+        );
+        push(&conn, "qux", json!(3), Action::AppendCapped { max_len: 2 });
+
+        let snapshot = db.snapshot();
+        assert_eq!(SequenceNumber(7), snapshot.sequence_number);
+        assert_eq!(Some(SequenceNumber(1)), snapshot.keys[1].compacted_through);
+        assert_eq!(Some(SequenceNumber(5)), snapshot.keys[2].compacted_through);
+        assert_eq!(SequenceNumber(6), snapshot.keys[2].data[0].seq);
+
+        let db2 = Database::new();
+        let (stash, callback) = MessageStash::new();
+        let conn2 = db2.connect(callback);
+        push(&conn2, "other", json!(0), Action::Replace);
+        subscribe(&conn2, "foo");
+        stash.next();
+
+        db2.restore(snapshot.clone()).unwrap();
+
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "foo".into(),
+                data: vec![
+                    SequenceValue {
+                        value: json_to_cbor(json!({ "moo": "ram" })),
+                        seq: SequenceNumber(1),
+                    },
+                    SequenceValue {
+                        value: json_to_cbor(json!({ "abc": "def" })),
+                        seq: SequenceNumber(2),
+                    }
+                ],
+                cursor: None,
+                compacted_through: Some(SequenceNumber(1)),
+            }),
+            stash.next()
+        );
+        assert_eq!(snapshot, db2.snapshot());
+        assert_eq!(
+            Some(SequenceNumber(5)),
+            db2.inner
+                .lock()
+                .unwrap()
+                .store
+                .compacted_through(&"qux".into())
+        );
+    }
+
+    #[test]
+    fn test_append_capped() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+
+        for i in 1..=4 {
+            push(&conn, "foo", json!(i), Action::AppendCapped { max_len: 2 });
+        }
+
+        assert_eq!(
+            Some(MessageFromDatabase::StreamSize {
+                key: "foo".into(),
+                size: 2,
+            }),
+            std::iter::from_fn(|| stash.next()).last()
+        );
+
+        let (stash2, callback2) = MessageStash::new();
+        let conn2 = db.connect(callback2);
+        subscribe(&conn2, "foo");
+
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "foo".into(),
+                data: vec![
+                    SequenceValue {
+                        value: json_to_cbor(json!(3)),
+                        seq: SequenceNumber(3),
+                    },
+                    SequenceValue {
+                        value: json_to_cbor(json!(4)),
+                        seq: SequenceNumber(4),
+                    }
+                ],
+                cursor: None,
+                compacted_through: None,
+            }),
+            stash2.next()
+        );
+    }
+
+    #[test]
+    fn test_ttl() {
+        let mut db = Database::new();
+        let now = Arc::new(AtomicU64::new(1_000));
+        {
+            let now = now.clone();
+            db.set_clock(move || now.load(Ordering::SeqCst));
+        }
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+
+        conn.send_message(&MessageToDatabase::SetTtl {
+            key: "foo".into(),
+            ttl: Some(500),
+        })
+        .unwrap();
+        push(&conn, "foo", json!(1), Action::Append);
+        conn.send_message(&MessageToDatabase::Push {
+            key: "foo".into(),
+            value: json_to_cbor(json!(2)),
+            action: Action::Append,
+            ttl: Some(100),
+        })
+        .unwrap();
+        push(&conn, "bar", json!(3), Action::Append);
+
+        // Values expire in stream order, so the second value waits for the first.
+        assert_eq!(Some(1_500), db.next_expiry());
+
+        // Time-to-lives and expiry times are kept in snapshots.
+        let db2 = Database::new();
+        db2.restore(db.snapshot()).unwrap();
+        assert_eq!(Some(1_500), db2.next_expiry());
+        assert_eq!(
+            Some(500),
+            db2.inner.lock().unwrap().store.key_ttl(&"foo".into())
+        );
+
+        now.store(1_200, Ordering::SeqCst);
+        assert_eq!(Some(1_500), db.purge_expired());
+
+        subscribe(&conn, "foo");
+        while stash.next().is_some() {}
+
+        now.store(1_500, Ordering::SeqCst);
+        assert_eq!(None, db.purge_expired());
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "foo".into(),
+                data: vec![],
+                cursor: None,
+                compacted_through: None,
+            }),
+            stash.next()
+        );
+
+        // Values without a TTL are kept.
+        let (stash2, callback2) = MessageStash::new();
+        let conn2 = db.connect(callback2);
+        subscribe(&conn2, "bar");
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "bar".into(),
+                data: vec![SequenceValue {
+                    value: json_to_cbor(json!(3)),
+                    seq: SequenceNumber(3),
+                }],
+                cursor: None,
+                compacted_through: None,
+            }),
+            stash2.next()
+        );
+    }
+
+    #[test]
+    fn test_ttl_expiry_order() {
+        let db = Database::new();
+        let conn = db.connect(|_| ());
+        let push_ttl = |value: i32, action: Action, ttl: Option<u64>| {
+            conn.send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!(value)),
+                action,
+                ttl,
+            })
+            .unwrap()
+        };
+        let conflict =
+            |reason: &str| Some(Error::Conflict("foo".into(), reason.into()).to_message(None));
+
+        // A value which expires can not be pushed behind one which never does.
+        assert_eq!(None, push_ttl(1, Action::Append, None));
+        assert_eq!(
+            conflict("1 never expires, and values expire in stream order"),
+            push_ttl(2, Action::Append, Some(100))
+        );
+
+        // Unless that value is trimmed by the same push.
+        push_ttl(2, Action::AppendCapped { max_len: 1 }, Some(100));
+        push_ttl(3, Action::Append, Some(100));
+
+        // Nor can a compaction which never expires be put in front of values
+        // which do.
+        let compact = Action::Compact {
+            seq: SequenceNumber(2),
+        };
+        assert_eq!(
+            conflict("the compacted value never expires, but 3 after it does"),
+            push_ttl(4, compact.clone(), None)
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::StreamSize {
+                key: "foo".into(),
+                size: 2,
+            }),
+            push_ttl(4, compact, Some(100))
+        );
+
+        // Atomic batches are checked in order.
+        let op = |value: i32, ttl| PushOp {
+            key: "foo".into(),
+            value: json_to_cbor(json!(value)),
+            action: Action::Append,
+            ttl,
+        };
+        assert_eq!(
+            Some(MessageFromDatabase::Batch {
+                messages: vec![
+                    conflict("4 never expires, and values expire in stream order").unwrap()
+                ],
+            }),
+            conn.send_message(&MessageToDatabase::Batch {
+                ops: vec![op(5, None), op(6, Some(100))],
+                atomic: true,
+            })
+            .unwrap()
+        );
+        assert_eq!(2, db.inner.lock().unwrap().store.stream_size(&"foo".into()));
+    }
+
+    #[test]
+    fn test_replace_if() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        subscribe(&conn, "foo");
+        stash.next();
+
+        // A stream is expected to be empty with a sequence number of zero.
+        push(
+            &conn,
+            "foo",
+            json!(1),
+            Action::ReplaceIf {
+                expected_seq: SequenceNumber(0),
+            },
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!(1)),
+                seq: SequenceNumber(1),
+                relay: false,
+            }),
+            stash.next()
+        );
+
+        push(
+            &conn,
+            "foo",
+            json!(2),
+            Action::ReplaceIf {
+                expected_seq: SequenceNumber(0),
+            },
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::Conflict {
+                key: "foo".into(),
+                expected_seq: SequenceNumber(0),
+                current: Some(SequenceValue {
+                    value: json_to_cbor(json!(1)),
+                    seq: SequenceNumber(1),
+                }),
+                request_id: None,
+            }),
+            stash.next()
+        );
+        assert_eq!(None, stash.next());
+
+        push(
+            &conn,
+            "foo",
+            json!(3),
+            Action::ReplaceIf {
+                expected_seq: SequenceNumber(1),
+            },
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!(3)),
+                seq: SequenceNumber(2),
+                relay: false,
+            }),
+            stash.next()
+        );
+    }
+
+    #[test]
+    fn test_batch() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        subscribe(&conn, "index");
+        subscribe(&conn, "item");
+        stash.next();
+        stash.next();
+
+        let op = |key: &str, value: serde_json::Value, action: Action| PushOp {
+            key: key.into(),
+            value: json_to_cbor(value),
+            action,
+            ttl: None,
+        };
+
+        // An atomic batch with a failed precondition is rejected as a whole.
+        conn.send_message(&MessageToDatabase::Batch {
+            ops: vec![
+                op("item", json!("a"), Action::Append),
+                op(
+                    "index",
+                    json!(["a"]),
+                    Action::ReplaceIf {
+                        expected_seq: SequenceNumber(4),
+                    },
+                ),
+            ],
+            atomic: true,
+        })
+        .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Batch {
+                messages: vec![MessageFromDatabase::Conflict {
+                    key: "index".into(),
+                    expected_seq: SequenceNumber(4),
+                    current: None,
+                    request_id: None,
+                }]
+            }),
+            stash.next()
+        );
+        assert_eq!(None, stash.next());
+
+        conn.send_message(&MessageToDatabase::Batch {
+            ops: vec![
+                op("item", json!("a"), Action::Append),
+                op(
+                    "index",
+                    json!(["a"]),
+                    Action::ReplaceIf {
+                        expected_seq: SequenceNumber(0),
+                    },
+                ),
+            ],
+            atomic: true,
+        })
+        .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Batch {
+                messages: vec![
+                    MessageFromDatabase::Push {
+                        key: "item".into(),
+                        value: json_to_cbor(json!("a")),
+                        seq: SequenceNumber(1),
+                        relay: false,
+                    },
+                    MessageFromDatabase::Push {
+                        key: "index".into(),
+                        value: json_to_cbor(json!(["a"])),
+                        seq: SequenceNumber(2),
+                        relay: false,
+                    },
+                ]
+            }),
+            stash.next()
+        );
+        assert_eq!(None, stash.next());
+
+        // Each precondition is checked against the state left by the pushes
+        // before it, so the second compaction is stale, and nothing is applied.
+        let compact = || {
+            op(
+                "index",
+                json!(["a"]),
+                Action::Compact {
+                    seq: SequenceNumber(2),
+                },
+            )
+        };
+        conn.send_message(&MessageToDatabase::Batch {
+            ops: vec![op("item", json!("b"), Action::Append), compact(), compact()],
+            atomic: true,
+        })
+        .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Batch {
+                messages: vec![Error::Conflict(
+                    "index".into(),
+                    "already compacted through 2".into()
+                )
+                .to_message(None)]
+            }),
+            stash.next()
+        );
+        assert_eq!(None, stash.next());
+
+        // Of two pushes expecting the same empty stream, only the first can apply.
+        let replace_if_empty = |value| {
+            op(
+                "lock",
+                value,
+                Action::ReplaceIf {
+                    expected_seq: SequenceNumber(0),
+                },
+            )
+        };
+        let result = conn
+            .send_message(&MessageToDatabase::Batch {
+                ops: vec![replace_if_empty(json!(1)), replace_if_empty(json!(2))],
+                atomic: true,
+            })
+            .unwrap();
+        assert!(matches!(
+            result,
+            Some(MessageFromDatabase::Batch { messages })
+                if matches!(messages[..], [MessageFromDatabase::Conflict { .. }])
+        ));
+
+        let inner = db.inner.lock().unwrap();
+        assert_eq!(1, inner.store.stream_size(&"item".into()));
+        assert_eq!(None, inner.store.compacted_through(&"index".into()));
+        assert_eq!(0, inner.store.stream_size(&"lock".into()));
+    }
+
+    #[test]
+    fn test_prefix_subscription() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        let conn2 = db.connect(|_| ());
+
+        push(&conn2, "cursor/a", json!(1), Action::Replace);
+        push(&conn2, "other", json!(2), Action::Replace);
+
+        conn.send_message(&MessageToDatabase::Get {
+            seq: Some(SequenceNumber::default()),
+            until_seq: None,
+            limit: None,
+            reverse: false,
+            chunk_size: None,
+            key: "cursor/".into(),
+            prefix: true,
+            subscribe: true,
+            compact_threshold: None,
+        })
+        .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Batch {
+                messages: vec![MessageFromDatabase::Init {
+                    key: "cursor/a".into(),
+                    data: vec![SequenceValue {
+                        value: json_to_cbor(json!(1)),
+                        seq: SequenceNumber(1),
+                    }],
+                    cursor: None,
+                    compacted_through: None,
+                }]
+            }),
+            stash.next()
+        );
+
+        // Keys created after subscribing are included, and a push is only
+        // received once even if the key is also subscribed to directly.
+        subscribe(&conn, "cursor/b");
+        stash.next();
+        push(&conn2, "cursor/b", json!(3), Action::Relay);
+        push(&conn2, "other", json!(4), Action::Relay);
+
+        assert_eq!(
+            Some(MessageFromDatabase::Push {
+                key: "cursor/b".into(),
+                value: json_to_cbor(json!(3)),
+                seq: SequenceNumber(3),
+                relay: true,
+            }),
+            stash.next()
+        );
+        assert_eq!(None, stash.next());
+    }
+
+    #[test]
+    fn test_unsubscribe() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        let conn2 = db.connect(|_| ());
+
+        // Subscribing twice does not duplicate pushes.
+        subscribe(&conn, "foo");
+        subscribe(&conn, "foo");
+        stash.next();
+        stash.next();
+
+        push(&conn2, "foo", json!(1), Action::Relay);
+        assert_eq!(
+            Some(MessageFromDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!(1)),
+                seq: SequenceNumber(1),
+                relay: true,
+            }),
+            stash.next()
+        );
+        assert_eq!(None, stash.next());
+
+        conn.send_message(&MessageToDatabase::Unsubscribe {
+            key: "foo".into(),
+            prefix: false,
+        })
+        .unwrap();
+        push(&conn2, "foo", json!(2), Action::Relay);
+        assert_eq!(None, stash.next());
+
+        // Subscriptions are removed when a connection is dropped.
+        subscribe(&conn2, "bar");
+        assert!(!db.inner.lock().unwrap().subscriptions.is_empty());
+        drop(conn2);
+        assert!(db.inner.lock().unwrap().subscriptions.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_key() {
+        let db = Database::new();
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+
+        let result = conn.send_message(&MessageToDatabase::Push {
+            key: "".into(),
+            value: json_to_cbor(json!(1)),
+            action: Action::Append,
+            ttl: None,
+        });
+        assert_eq!(
+            Err(Error::InvalidKey(
+                "".into(),
+                "keys may not be empty".to_string()
+            )),
+            result
+        );
+        assert_eq!(None, stash.next());
+    }
+
+    #[test]
+    /// Callbacks are called without the database locked, so they may use it.
+    fn test_reentrant_callback() {
+        let db = Database::new();
+        let (stash, callback) = MessageStash::new();
+        let conn = {
+            let db = db.clone();
+            db.clone().connect(move |message| {
+                db.next_expiry();
+                callback(message);
+            })
+        };
+
+        subscribe(&conn, "foo");
+        push(&conn, "foo", json!(1), Action::Append);
+
+        assert!(matches!(
+            stash.next(),
+            Some(MessageFromDatabase::Init { .. })
+        ));
+        assert!(matches!(
+            stash.next(),
+            Some(MessageFromDatabase::Push { .. })
+        ));
+        assert_eq!(None, stash.next());
+    }
+
+    #[test]
+    /// A broadcast is encoded once and shared by every subscriber.
+    fn test_shared_frame() {
+        let db = Database::new();
+        let encoded = Arc::new(Mutex::new(Vec::new()));
+        let connect = || {
+            let encoded = encoded.clone();
+            db.connect_frames(move |frame| {
+                let is_push = |message: &MessageFromDatabase| match message {
+                    MessageFromDatabase::Push { .. } => true,
+                    MessageFromDatabase::Batch { messages } => {
+                        matches!(messages[0], MessageFromDatabase::Push { .. })
+                    }
+                    _ => false,
+                };
+                if is_push(frame.message()) {
+                    encoded
+                        .lock()
+                        .unwrap()
+                        .push(frame.encode(Encoding::Json).unwrap());
+                }
+            })
+        };
+        let conn1 = connect();
+        let conn2 = connect();
+        let conn3 = connect();
+
+        subscribe_compactor(&conn1, "foo", Some(1));
+        subscribe(&conn2, "foo");
+        subscribe(&conn3, "foo");
+        push(&conn1, "foo", json!(1), Action::Append);
+
+        // So is the `Batch` of a batch write, except with the compactor, whose
+        // batch also requests compaction.
+        let op = PushOp {
+            key: "foo".into(),
+            value: json_to_cbor(json!(2)),
+            action: Action::Append,
+            ttl: None,
+        };
+        conn1
+            .send_message(&MessageToDatabase::Batch {
+                ops: vec![op.clone(), op],
+                atomic: false,
+            })
+            .unwrap();
+
+        let encoded = encoded.lock().unwrap();
+        assert_eq!(6, encoded.len());
+        assert!(Arc::ptr_eq(&encoded[0], &encoded[1]));
+        assert!(Arc::ptr_eq(&encoded[0], &encoded[2]));
+        assert!(!Arc::ptr_eq(&encoded[3], &encoded[4]));
+        assert!(Arc::ptr_eq(&encoded[4], &encoded[5]));
+    }
+
+    #[test]
+    /// Pushed values are shared by the store and every message, not copied.
+    fn test_values_are_shared() {
+        let db = Database::new();
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+
+        subscribe(&conn, "foo");
+        stash.next();
+
+        let value = json_to_cbor(json!({"text": "hello"}));
+        conn.send_message(&MessageToDatabase::Push {
+            key: "foo".into(),
+            value: value.clone(),
+            action: Action::Append,
+            ttl: None,
+        })
+        .unwrap();
+
+        let Some(MessageFromDatabase::Push { value: pushed, .. }) = stash.next() else {
+            panic!("Expected a push.");
+        };
+        assert!(Arc::ptr_eq(&value, &pushed));
+
+        let stored = db
+            .inner
+            .lock()
+            .unwrap()
+            .store
+            .get(&"foo".into(), SequenceNumber::default());
+        assert!(Arc::ptr_eq(&value, &stored[0].value));
+    }
+
+    #[test]
+    fn test_list_keys() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+
+        push(&conn, "doc/b", json!(1), Action::Append);
+        push(&conn, "doc/a", json!(2), Action::Append);
+        push(&conn, "doc/a", json!(3), Action::Append);
+        push(&conn, "doc/c", json!(4), Action::Relay);
+        push(&conn, "other", json!(5), Action::Replace);
+        while stash.next().is_some() {}
+
+        conn.send_message(&MessageToDatabase::ListKeys {
+            prefix: "doc/".to_string(),
+            cursor: None,
+            limit: Some(1),
+        })
+        .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Keys {
+                keys: vec![KeyInfo {
+                    key: "doc/a".into(),
+                    size: 2,
+                    latest_seq: SequenceNumber(3),
+                }],
+                next_cursor: Some("doc/a".into()),
+            }),
+            stash.next()
+        );
+
+        conn.send_message(&MessageToDatabase::ListKeys {
+            prefix: "doc/".to_string(),
+            cursor: Some("doc/a".into()),
+            limit: Some(1),
+        })
+        .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Keys {
+                keys: vec![KeyInfo {
+                    key: "doc/b".into(),
+                    size: 1,
+                    latest_seq: SequenceNumber(1),
+                }],
+                next_cursor: None,
+            }),
+            stash.next()
+        );
+
+        // A limit of zero still makes progress.
+        conn.send_message(&MessageToDatabase::ListKeys {
+            prefix: String::new(),
+            cursor: Some("doc/a".into()),
+            limit: Some(0),
+        })
+        .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Keys {
+                keys: vec![KeyInfo {
+                    key: "doc/b".into(),
+                    size: 1,
+                    latest_seq: SequenceNumber(1),
+                }],
+                next_cursor: Some("doc/b".into()),
+            }),
+            stash.next()
+        );
+    }
+
+    #[test]
+    fn test_delete() {
+        let mut db = Database::new();
+        let replicated = Arc::new(Mutex::new(Vec::new()));
+        {
+            let replicated = replicated.clone();
+            db.set_replica_callback(move |result| {
+                replicated
+                    .lock()
+                    .unwrap()
+                    .push(result.delete_instruction.clone());
+            });
+        }
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        subscribe(&conn, "foo");
+        stash.next();
+
+        push(&conn, "foo", json!(1), Action::Append);
+        stash.next();
+        push(&conn, "foo", json!(null), Action::Delete);
+
+        assert_eq!(
+            Some(MessageFromDatabase::Deleted {
+                key: "foo".into(),
+                seq: SequenceNumber(2),
+            }),
+            stash.next()
+        );
+        assert_eq!(
+            Some(DeleteInstruction::Delete),
+            replicated.lock().unwrap().last().cloned().flatten()
+        );
+        assert!(db.snapshot().keys.is_empty());
+    }
+
+    #[test]
+    fn test_get_without_subscribing() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        push(&conn, "foo", json!(1), Action::Replace);
+        stash.next();
+
+        let response = conn
+            .send_message(&MessageToDatabase::Get {
+                seq: Some(SequenceNumber::default()),
+                until_seq: None,
+                limit: None,
+                reverse: false,
+                chunk_size: None,
+                key: "foo".into(),
+                prefix: false,
+                subscribe: false,
+                compact_threshold: None,
+            })
+            .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "foo".into(),
+                data: vec![SequenceValue {
+                    value: json_to_cbor(json!(1)),
+                    seq: SequenceNumber(1),
+                }],
+                cursor: None,
+                compacted_through: None,
+            }),
+            response
+        );
+        stash.next();
+
+        push(&conn, "foo", json!(2), Action::Replace);
+        assert_eq!(None, stash.next());
+        assert!(db.inner.lock().unwrap().subscriptions.is_empty());
+    }
+
+    #[test]
+    fn test_get_range() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        for i in 1..=5 {
+            push(&conn, "log", json!(i), Action::Append);
+        }
+        while stash.next().is_some() {}
+
+        let get_limit = |seq: u64, until_seq: Option<u64>, reverse: bool, limit: usize| {
+            conn.send_message(&MessageToDatabase::Get {
+                seq: Some(SequenceNumber(seq)),
+                until_seq: until_seq.map(SequenceNumber),
+                limit: Some(limit),
+                reverse,
+                chunk_size: None,
+                key: "log".into(),
+                prefix: false,
+                subscribe: false,
+                compact_threshold: None,
+            })
+            .unwrap()
+        };
+        let get = |seq, until_seq, reverse| get_limit(seq, until_seq, reverse, 2);
+        let init = |seqs: &[u64], cursor: Option<u64>| {
+            Some(MessageFromDatabase::Init {
+                key: "log".into(),
+                data: seqs
+                    .iter()
+                    .map(|seq| SequenceValue {
+                        value: json_to_cbor(json!(seq)),
+                        seq: SequenceNumber(*seq),
+                    })
+                    .collect(),
+                cursor: cursor.map(SequenceNumber),
+                compacted_through: None,
+            })
+        };
+
+        // Page forwards.
+        assert_eq!(init(&[1, 2], Some(2)), get(0, None, false));
+        assert_eq!(init(&[3, 4], Some(4)), get(2, None, false));
+        assert_eq!(init(&[5], None), get(4, None, false));
+
+        // Page backwards from the most recent value.
+        assert_eq!(init(&[5, 4], Some(3)), get(0, None, true));
+        assert_eq!(init(&[3, 2], Some(1)), get(0, Some(3), true));
+        assert_eq!(init(&[1], None), get(0, Some(1), true));
+
+        // Bounded on both sides.
+        assert_eq!(init(&[3], None), get(2, Some(3), false));
+
+        // A limit of zero returns no values, but only ends the read if none remain.
+        assert_eq!(init(&[], Some(2)), get_limit(2, None, false, 0));
+        assert_eq!(init(&[], None), get_limit(5, None, false, 0));
+        assert_eq!(init(&[], Some(5)), get_limit(0, None, true, 0));
+        assert_eq!(init(&[], Some(3)), get_limit(0, Some(3), true, 0));
+    }
+
+    #[test]
+    fn test_chunked_init() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+        for i in 1..=3 {
+            push(&conn, "log", json!(i), Action::Append);
+        }
+        while stash.next().is_some() {}
+
+        let value = |seq: u64| SequenceValue {
+            value: json_to_cbor(json!(seq)),
+            seq: SequenceNumber(seq),
+        };
+
+        conn.send_message(&MessageToDatabase::Get {
+            seq: Some(SequenceNumber::default()),
+            until_seq: None,
+            limit: None,
+            reverse: false,
+            chunk_size: Some(2),
+            key: "log".into(),
+            prefix: false,
+            subscribe: true,
+            compact_threshold: None,
+        })
+        .unwrap();
+        push(&conn, "log", json!(4), Action::Relay);
+
+        assert_eq!(
+            Some(MessageFromDatabase::InitChunk {
+                key: "log".into(),
+                data: vec![value(1), value(2)],
+                last: false,
+                cursor: None,
+                compacted_through: None,
+            }),
+            stash.next()
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::InitChunk {
+                key: "log".into(),
+                data: vec![value(3)],
+                last: true,
+                cursor: None,
+                compacted_through: None,
+            }),
+            stash.next()
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::Push {
+                key: "log".into(),
+                value: json_to_cbor(json!(4)),
+                seq: SequenceNumber(4),
+                relay: true,
+            }),
+            stash.next()
+        );
+        while stash.next().is_some() {}
+
+        let get = |limit, reverse, prefix| MessageToDatabase::Get {
+            seq: Some(SequenceNumber::default()),
+            until_seq: None,
+            limit,
+            reverse,
+            chunk_size: Some(1),
+            key: if prefix { "lo".into() } else { "log".into() },
+            prefix,
+            subscribe: false,
+            compact_threshold: None,
+        };
+
+        // A limited read ends with the cursor to continue from.
+        conn.send_message(&get(Some(2), true, false)).unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::InitChunk {
+                key: "log".into(),
+                data: vec![value(3)],
+                last: false,
+                cursor: None,
+                compacted_through: None,
+            }),
+            stash.next()
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::InitChunk {
+                key: "log".into(),
+                data: vec![value(2)],
+                last: true,
+                cursor: Some(SequenceNumber(1)),
+                compacted_through: None,
+            }),
+            stash.next()
+        );
+
+        // So does a read with a limit of zero, which returns no values.
+        conn.send_message(&get(Some(0), false, false)).unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "log".into(),
+                data: vec![],
+                cursor: Some(SequenceNumber(0)),
+                compacted_through: None,
+            }),
+            stash.next()
+        );
+
+        // Prefix reads are chunked per key.
+        conn.send_message(&get(None, false, true)).unwrap();
+        for seq in 1..=3 {
+            assert_eq!(
+                Some(MessageFromDatabase::InitChunk {
+                    key: "log".into(),
+                    data: vec![value(seq)],
+                    last: seq == 3,
+                    cursor: None,
+                    compacted_through: None,
+                }),
+                stash.next()
+            );
+        }
+
+        // One-off requests return the whole read as their single response.
+        let init = MessageFromDatabase::Init {
+            key: "log".into(),
+            data: vec![value(1), value(2), value(3)],
+            cursor: None,
+            compacted_through: None,
+        };
+        assert_eq!(
+            Some(init.clone()),
+            conn.send_message(&get(None, false, false).without_subscription())
+                .unwrap()
+        );
+        assert_eq!(Some(init), stash.next());
+        assert_eq!(None, stash.next());
+    }
+
+    #[test]
+    fn test_compact() {
+        let db = Database::new();
+
+        let (stash, callback) = MessageStash::new();
+        let conn = db.connect(callback);
+
+        subscribe(&conn, "foo");
+
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                data: vec![],
+                key: "foo".into(),
+                cursor: None,
+                compacted_through: None,
+            }),
+            stash.next()
+        );
+
+        push(&conn, "foo", json!({ "bar": "baz" }), Action::Append);
+        push(&conn, "foo", json!({ "abc": "def" }), Action::Append);
+        push(&conn, "foo", json!({ "boo": "baa" }), Action::Append);
+        push(
+            &conn,
+            "foo",
+            json!({ "moo": "ram" }),
+            Action::Compact {
+                seq: SequenceNumber(2),
+            },
+        );
+
+        // The durable message should be sent to new subscriptions.
+        let (stash2, callback2) = MessageStash::new();
+        let conn2 = db.connect(callback2);
 
         subscribe(&conn2, "foo");
 
@@ -555,9 +2367,464 @@ mod tests {
                         value: json_to_cbor(json!({ "boo": "baa" })),
                         seq: SequenceNumber(3),
                     }
-                ]
+                ],
+                cursor: None,
+                compacted_through: Some(SequenceNumber(2)),
+            }),
+            stash2.next()
+        );
+    }
+
+    #[test]
+    fn test_compactor_election() {
+        let db = Database::new();
+        let writer = db.connect(|_| ());
+
+        // Subscribers which do not volunteer are never elected.
+        let (viewer_stash, viewer_callback) = MessageStash::new();
+        let viewer = db.connect(viewer_callback);
+        subscribe(&viewer, "foo");
+        viewer_stash.next();
+
+        let (stash1, callback1) = MessageStash::new();
+        let conn1 = db.connect(callback1);
+        subscribe_compactor(&conn1, "foo", Some(1));
+        stash1.next();
+
+        let (stash2, callback2) = MessageStash::new();
+        let conn2 = db.connect(callback2);
+        subscribe_compactor(&conn2, "foo", Some(1));
+        stash2.next();
+
+        push(&writer, "foo", json!(1), Action::Append);
+        push(&writer, "foo", json!(2), Action::Append);
+
+        // Only the earliest volunteer is asked to compact.
+        stash1.next();
+        stash1.next();
+        assert_eq!(
+            Some(MessageFromDatabase::CompactionRequested {
+                key: "foo".into(),
+                size: 2,
+            }),
+            stash1.next()
+        );
+        stash2.next();
+        stash2.next();
+        assert_eq!(None, stash2.next());
+
+        // When the compactor disconnects, the next volunteer takes over.
+        drop(conn1);
+        assert_eq!(
+            Some(MessageFromDatabase::CompactionRequested {
+                key: "foo".into(),
+                size: 2,
             }),
             stash2.next()
         );
+
+        let (stash3, callback3) = MessageStash::new();
+        let conn3 = db.connect(callback3);
+        subscribe_compactor(&conn3, "foo", Some(1));
+        stash3.next();
+
+        // Likewise when it unsubscribes.
+        conn2
+            .send_message(&MessageToDatabase::Unsubscribe {
+                key: "foo".into(),
+                prefix: false,
+            })
+            .unwrap();
+        assert_eq!(
+            Some(MessageFromDatabase::CompactionRequested {
+                key: "foo".into(),
+                size: 2,
+            }),
+            stash3.next()
+        );
+
+        push(&writer, "foo", json!(3), Action::Append);
+        stash3.next();
+        assert_eq!(
+            Some(MessageFromDatabase::CompactionRequested {
+                key: "foo".into(),
+                size: 3,
+            }),
+            stash3.next()
+        );
+        assert_eq!(None, stash2.next());
+
+        // The compactor is only asked once the stream grows past its threshold,
+        // which a later `Get` can change.
+        subscribe_compactor(&conn3, "foo", Some(4));
+        stash3.next();
+        push(&writer, "foo", json!(4), Action::Append);
+        stash3.next();
+        assert_eq!(None, stash3.next());
+        push(&writer, "foo", json!(5), Action::Append);
+        stash3.next();
+        assert_eq!(
+            Some(MessageFromDatabase::CompactionRequested {
+                key: "foo".into(),
+                size: 5,
+            }),
+            stash3.next()
+        );
+
+        while viewer_stash.next().is_some() {}
+        drop(conn3);
+        assert_eq!(None, viewer_stash.next());
+    }
+
+    #[test]
+    fn test_compact_notifies_subscribers() {
+        let db = Database::new();
+        let conn = db.connect(|_| ());
+
+        push(&conn, "foo", json!({ "bar": "baz" }), Action::Append);
+        push(&conn, "foo", json!({ "abc": "def" }), Action::Append);
+
+        let (stash, callback) = MessageStash::new();
+        let subscriber = db.connect(callback);
+        subscribe(&subscriber, "foo");
+        stash.next();
+
+        let (debug_stash, debug_callback) = MessageStash::new();
+        let _debug = db.connect_debug(debug_callback);
+        debug_stash.next();
+
+        push(
+            &conn,
+            "foo",
+            json!({ "moo": "ram" }),
+            Action::Compact {
+                seq: SequenceNumber(2),
+            },
+        );
+
+        assert_eq!(
+            Some(MessageFromDatabase::Compacted {
+                key: "foo".into(),
+                seq: SequenceNumber(2),
+            }),
+            stash.next()
+        );
+        assert_eq!(None, stash.next());
+
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "foo".into(),
+                data: vec![SequenceValue {
+                    value: json_to_cbor(json!({ "moo": "ram" })),
+                    seq: SequenceNumber(2),
+                }],
+                cursor: None,
+                compacted_through: Some(SequenceNumber(2)),
+            }),
+            debug_stash.next()
+        );
+        assert_eq!(
+            Some(MessageFromDatabase::Compacted {
+                key: "foo".into(),
+                seq: SequenceNumber(2),
+            }),
+            debug_stash.next()
+        );
+
+        // A rejected compaction is not announced.
+        push(
+            &conn,
+            "foo",
+            json!({ "stale": true }),
+            Action::Compact {
+                seq: SequenceNumber(1),
+            },
+        );
+        assert_eq!(None, stash.next());
+        assert_eq!(None, debug_stash.next());
+    }
+
+    #[test]
+    fn test_compact_rejects_stale_and_out_of_range() {
+        let db = Database::new();
+
+        let conn = db.connect(|_| ());
+
+        push(&conn, "foo", json!({ "bar": "baz" }), Action::Append);
+        push(&conn, "foo", json!({ "abc": "def" }), Action::Append);
+        push(&conn, "foo", json!({ "boo": "baa" }), Action::Append);
+        push(
+            &conn,
+            "foo",
+            json!({ "moo": "ram" }),
+            Action::Compact {
+                seq: SequenceNumber(2),
+            },
+        );
+
+        let compact = |seq: u64| {
+            conn.send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!({ "stale": true })),
+                action: Action::Compact {
+                    seq: SequenceNumber(seq),
+                },
+                ttl: None,
+            })
+            .unwrap()
+        };
+
+        // A compaction at or before the watermark is stale.
+        assert_eq!(
+            Some(
+                Error::Conflict("foo".into(), "already compacted through 2".into())
+                    .to_message(None)
+            ),
+            compact(1)
+        );
+        assert_eq!(
+            Some(
+                Error::Conflict("foo".into(), "already compacted through 2".into())
+                    .to_message(None)
+            ),
+            compact(2)
+        );
+
+        // A compaction past the most recent value has nothing to cover.
+        assert_eq!(
+            Some(
+                Error::Conflict("foo".into(), "5 is after the most recent value, 3".into())
+                    .to_message(None)
+            ),
+            compact(5)
+        );
+
+        // Rejected compactions leave the stream unchanged.
+        let (stash, callback) = MessageStash::new();
+        let conn2 = db.connect(callback);
+        subscribe(&conn2, "foo");
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "foo".into(),
+                data: vec![
+                    SequenceValue {
+                        value: json_to_cbor(json!({ "moo": "ram" })),
+                        seq: SequenceNumber(2),
+                    },
+                    SequenceValue {
+                        value: json_to_cbor(json!({ "boo": "baa" })),
+                        seq: SequenceNumber(3),
+                    }
+                ],
+                cursor: None,
+                compacted_through: Some(SequenceNumber(2)),
+            }),
+            stash.next()
+        );
+
+        // A later compaction advances the watermark.
+        assert_eq!(None, compact(3));
+        assert_eq!(
+            Some(SequenceNumber(3)),
+            db.inner
+                .lock()
+                .unwrap()
+                .store
+                .compacted_through(&"foo".into())
+        );
+
+        // Replacing the stream keeps the watermark, and a compaction through
+        // the replaced values is stale.
+        push(&conn, "foo", json!(4), Action::Append);
+        push(&conn, "foo", json!(5), Action::Replace);
+        assert_eq!(
+            Some(
+                Error::Conflict("foo".into(), "already compacted through 3".into())
+                    .to_message(None)
+            ),
+            compact(2)
+        );
+        assert_eq!(
+            Some(
+                Error::Conflict(
+                    "foo".into(),
+                    "4 is before the oldest value retained, 5".into()
+                )
+                .to_message(None)
+            ),
+            compact(4)
+        );
+
+        // So is a compaction through values trimmed from the head.
+        for i in 1..=5 {
+            push(&conn, "bar", json!(i), Action::AppendCapped { max_len: 2 });
+        }
+        assert_eq!(
+            Some(
+                Error::Conflict(
+                    "bar".into(),
+                    "7 is before the oldest value retained, 9".into()
+                )
+                .to_message(None)
+            ),
+            conn.send_message(&MessageToDatabase::Push {
+                key: "bar".into(),
+                value: json_to_cbor(json!({ "stale": true })),
+                action: Action::Compact {
+                    seq: SequenceNumber(7),
+                },
+                ttl: None,
+            })
+            .unwrap()
+        );
+
+        // Only deleting the key resets the watermark.
+        push(&conn, "foo", json!(6), Action::Delete);
+        push(&conn, "foo", json!(7), Action::Append);
+        assert_eq!(
+            None,
+            db.inner
+                .lock()
+                .unwrap()
+                .store
+                .compacted_through(&"foo".into())
+        );
+        assert_eq!(None, compact(12));
+    }
+
+    /// A backend which keeps values in memory, but fails to persist changes.
+    /// Fails to persist every change after the first `.1`.
+    struct FailingBackend(MemoryBackend, usize);
+
+    impl StorageBackend for FailingBackend {
+        fn load(&mut self) -> SequenceNumber {
+            self.0.load()
+        }
+
+        fn append(&mut self, key: &Key, value: SequenceValue) {
+            self.0.append(key, value)
+        }
+
+        fn push_front(&mut self, key: &Key, value: SequenceValue) {
+            self.0.push_front(key, value)
+        }
+
+        fn set_compacted_through(&mut self, key: &Key, seq: SequenceNumber) {
+            self.0.set_compacted_through(key, seq)
+        }
+
+        fn delete(&mut self, key: &Key) {
+            self.0.delete(key)
+        }
+
+        fn delete_up_to(&mut self, key: &Key, seq: SequenceNumber) {
+            self.0.delete_up_to(key, seq)
+        }
+
+        fn get(&self, key: &Key, min_sequence: SequenceNumber) -> Vec<SequenceValue> {
+            self.0.get(key, min_sequence)
+        }
+
+        fn len(&self, key: &Key) -> usize {
+            self.0.len(key)
+        }
+
+        fn seq_at(&self, key: &Key, index: usize) -> Option<SequenceNumber> {
+            self.0.seq_at(key, index)
+        }
+
+        fn compacted_through(&self, key: &Key) -> Option<SequenceNumber> {
+            self.0.compacted_through(key)
+        }
+
+        fn keys(&self) -> Vec<Key> {
+            self.0.keys()
+        }
+
+        fn apply(&mut self, result: &ApplyResult) -> Result<(), StorageError> {
+            if self.1 == 0 {
+                return Err(StorageError("disk full".to_string()));
+            }
+            self.1 -= 1;
+            result.apply_to(&mut self.0);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_unpersisted_push_rejected() {
+        let db = Database::new_from_store(Store::with_backend(FailingBackend(
+            MemoryBackend::default(),
+            0,
+        )));
+
+        let (stash, callback) = MessageStash::new();
+        let subscriber = db.connect(callback);
+        subscribe(&subscriber, "foo");
+        assert_eq!(
+            Some(MessageFromDatabase::Init {
+                key: "foo".into(),
+                data: vec![],
+                cursor: None,
+                compacted_through: None,
+            }),
+            stash.next()
+        );
+
+        let conn = db.connect(|_| ());
+        let result = conn
+            .send_message(&MessageToDatabase::Push {
+                key: "foo".into(),
+                value: json_to_cbor(json!(1)),
+                action: Action::Append,
+                ttl: None,
+            })
+            .unwrap();
+        assert_eq!(
+            Some(Error::Storage("disk full".to_string()).to_message(None)),
+            result
+        );
+
+        // The push is neither stored nor broadcast.
+        assert_eq!(None, stash.next());
+        assert!(db
+            .inner
+            .lock()
+            .unwrap()
+            .store
+            .get(&"foo".into(), SequenceNumber::default())
+            .is_empty());
+    }
+
+    #[test]
+    fn test_unpersisted_atomic_batch_stops() {
+        let db = Database::new_from_store(Store::with_backend(FailingBackend(
+            MemoryBackend::default(),
+            1,
+        )));
+
+        let conn = db.connect(|_| ());
+        let op = |value| PushOp {
+            key: "foo".into(),
+            value: json_to_cbor(value),
+            action: Action::Append,
+            ttl: None,
+        };
+        let result = conn
+            .send_message(&MessageToDatabase::Batch {
+                ops: vec![op(json!(1)), op(json!(2)), op(json!(3))],
+                atomic: true,
+            })
+            .unwrap();
+
+        // The push before the failed one stays applied, and the rest are
+        // not attempted.
+        assert_eq!(
+            Some(MessageFromDatabase::Batch {
+                messages: vec![Error::Storage("disk full".to_string()).to_message(None)]
+            }),
+            result
+        );
+        assert_eq!(1, db.inner.lock().unwrap().store.stream_size(&"foo".into()));
     }
 }
@@ -0,0 +1,136 @@
+use crate::{connection::Connection, frame::Frame, types::MessageFromDatabase};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+/// Messages to be delivered to each connection, in order. A message delivered
+/// to several connections is shared between them, so that it is only encoded
+/// once.
+#[derive(Default)]
+pub struct FanOut {
+    deliveries: Vec<(Arc<Connection>, Vec<Arc<Frame>>)>,
+
+    /// Position in `deliveries` of each connection, by address.
+    index: HashMap<usize, usize>,
+
+    /// Whether connections which receive more than one message receive them
+    /// in a single `Batch` message.
+    group: bool,
+}
+
+impl FanOut {
+    /// A fan-out which delivers the messages for each connection as a `Batch`.
+    pub fn grouped() -> Self {
+        FanOut {
+            group: true,
+            ..Default::default()
+        }
+    }
+
+    pub fn add(&mut self, conn: Arc<Connection>, frame: Arc<Frame>) {
+        let address = Arc::as_ptr(&conn) as usize;
+        match self.index.get(&address) {
+            Some(&i) => self.deliveries[i].1.push(frame),
+            None => {
+                self.index.insert(address, self.deliveries.len());
+                self.deliveries.push((conn, vec![frame]));
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deliveries.is_empty()
+    }
+
+    fn deliver(self) {
+        // Connections which receive the same frames share one `Batch` frame,
+        // by the addresses of its frames.
+        let mut batches: HashMap<Vec<usize>, Arc<Frame>> = HashMap::new();
+
+        for (conn, frames) in self.deliveries {
+            if self.group && frames.len() > 1 {
+                let addresses = frames.iter().map(|f| Arc::as_ptr(f) as usize).collect();
+                let batch = batches.entry(addresses).or_insert_with(|| {
+                    let messages = frames.iter().map(|f| f.message().clone()).collect();
+                    Arc::new(Frame::new(MessageFromDatabase::Batch { messages }))
+                });
+                (conn.callback)(batch);
+            } else {
+                for frame in &frames {
+                    (conn.callback)(frame);
+                }
+            }
+        }
+    }
+}
+
+#[derive(Default)]
+struct DispatchState {
+    queue: VecDeque<FanOut>,
+    delivering: bool,
+}
+
+/// Delivers the fan-outs of a database to connection callbacks outside of the
+/// database lock, in the order they were planned.
+///
+/// Fan-outs are queued while the database is locked, so the queue is in the
+/// order in which changes were applied. After unlocking the database, every
+/// thread which queued a fan-out calls [`Dispatcher::deliver`], but only one
+/// thread at a time drains the queue, so deliveries are never reordered. A
+/// callback which sends a message to the database therefore does not
+/// deadlock: its fan-out is delivered once the callback returns.
+#[derive(Default)]
+pub struct Dispatcher {
+    state: Mutex<DispatchState>,
+}
+
+impl Dispatcher {
+    /// Queue a fan-out. Must be called while the database is locked.
+    pub fn enqueue(&self, fan_out: FanOut) {
+        if !fan_out.is_empty() {
+            self.state.lock().unwrap().queue.push_back(fan_out);
+        }
+    }
+
+    /// Deliver every queued fan-out, unless another thread is already doing
+    /// so. Must be called while the database is not locked.
+    pub fn deliver(&self) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.delivering {
+                return;
+            }
+            state.delivering = true;
+        }
+        let _guard = PanicGuard(self);
+
+        loop {
+            let fan_out = {
+                let mut state = self.state.lock().unwrap();
+                match state.queue.pop_front() {
+                    Some(fan_out) => fan_out,
+                    None => {
+                        state.delivering = false;
+                        return;
+                    }
+                }
+            };
+
+            fan_out.deliver();
+        }
+    }
+}
+
+/// Lets other threads deliver again if a callback panics mid-delivery.
+struct PanicGuard<'a>(&'a Dispatcher);
+
+impl Drop for PanicGuard<'_> {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            if let Ok(mut state) = self.0.state.lock() {
+                state.delivering = false;
+            }
+        }
+    }
+}
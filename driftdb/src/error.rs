@@ -0,0 +1,234 @@
+use crate::{
+    backend::StorageError,
+    types::{ErrorCode, Key, MessageFromDatabase, SequenceNumber, SequenceValue},
+};
+use std::fmt::Display;
+
+/// An error which prevented a message from being handled.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    /// The database was dropped while the connection was still in use.
+    DatabaseGone,
+
+    /// A key was not valid; the reason is given.
+    InvalidKey(Key, String),
+
+    /// A limit on the size or number of messages or values was reached. The
+    /// database sets no limits itself; this is for servers which do.
+    QuotaExceeded(String),
+
+    /// A message could not be decoded.
+    Decode(String),
+
+    /// A write was rejected because of the state of the stream of a key; the
+    /// reason is given.
+    Conflict(Key, String),
+
+    /// A `ReplaceIf` push was rejected because the most recent value retained
+    /// for the key, given as `current`, does not have the expected sequence
+    /// number. Sent to clients as a `Conflict` message, so that they can retry
+    /// without reading the stream again.
+    ReplaceConflict {
+        key: Key,
+        expected_seq: SequenceNumber,
+        current: Option<SequenceValue>,
+    },
+
+    /// The client is not allowed to perform the operation. Like
+    /// `QuotaExceeded`, this is for servers which check permissions.
+    Unauthorized,
+
+    /// A write could not be persisted, so it was not applied.
+    Storage(String),
+
+    /// The connection fell behind on reading messages and was closed. Only
+    /// returned by `ConnectionSink`; it has no code of its own, since it is
+    /// never sent to clients, and shares that of `DatabaseGone`.
+    Disconnected,
+}
+
+impl Error {
+    /// A stable, machine-readable code for the error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Error::DatabaseGone | Error::Disconnected => ErrorCode::DatabaseGone,
+            Error::InvalidKey(..) => ErrorCode::InvalidKey,
+            Error::QuotaExceeded(_) => ErrorCode::QuotaExceeded,
+            Error::Decode(_) => ErrorCode::DecodeFailed,
+            Error::Conflict(..) | Error::ReplaceConflict { .. } => ErrorCode::Conflict,
+            Error::Unauthorized => ErrorCode::Unauthorized,
+            Error::Storage(_) => ErrorCode::StorageFailed,
+        }
+    }
+
+    /// The `Error` message to send to the client, in response to the request
+    /// with the given id, if any.
+    pub fn to_message(&self, request_id: Option<String>) -> MessageFromDatabase {
+        if let Error::ReplaceConflict {
+            key,
+            expected_seq,
+            current,
+        } = self
+        {
+            return MessageFromDatabase::Conflict {
+                key: key.clone(),
+                expected_seq: *expected_seq,
+                current: current.clone(),
+                request_id,
+            };
+        }
+
+        MessageFromDatabase::Error {
+            code: self.code(),
+            message: self.to_string(),
+            request_id,
+        }
+    }
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::DatabaseGone => write!(f, "Database is gone"),
+            Error::InvalidKey(key, reason) => {
+                write!(f, "Invalid key {:?}: {}", key.as_str(), reason)
+            }
+            Error::QuotaExceeded(message) => write!(f, "Quota exceeded: {}", message),
+            Error::Decode(message) => write!(f, "Could not decode message: {}", message),
+            Error::Conflict(key, reason) => {
+                write!(f, "Conflicting write to key {:?}: {}", key.as_str(), reason)
+            }
+            Error::ReplaceConflict {
+                key,
+                expected_seq,
+                current,
+            } => match current {
+                Some(current) => write!(
+                    f,
+                    "Conflicting write to key {:?}: expected the most recent value to be {}, but it is {}",
+                    key.as_str(),
+                    expected_seq.0,
+                    current.seq.0
+                ),
+                None => write!(
+                    f,
+                    "Conflicting write to key {:?}: expected the most recent value to be {}, but the stream is empty",
+                    key.as_str(),
+                    expected_seq.0
+                ),
+            },
+            Error::Unauthorized => write!(f, "Unauthorized"),
+            Error::Storage(message) => write!(f, "Could not persist write: {}", message),
+            Error::Disconnected => write!(f, "Connection fell behind and was closed"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<StorageError> for Error {
+    fn from(err: StorageError) -> Self {
+        Error::Storage(err.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Action, MessageToDatabase, PushOp, Request};
+    use ciborium::Value;
+    use serde_json::json;
+
+    #[test]
+    fn test_error_message() {
+        let message = Error::DatabaseGone.to_message(Some("abc".to_string()));
+        assert_eq!(
+            json!({
+                "type": "error",
+                "code": "database_gone",
+                "message": "Database is gone",
+                "request_id": "abc",
+            }),
+            serde_json::to_value(message).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_request_id() {
+        let request: Request = serde_json::from_value(json!({
+            "type": "push",
+            "key": "foo",
+            "value": 1,
+            "action": {"type": "append"},
+            "request_id": "abc",
+        }))
+        .unwrap();
+        assert_eq!(Some("abc".to_string()), request.request_id);
+
+        // Binary values survive decoding a CBOR request.
+        let request = Request {
+            request_id: None,
+            message: MessageToDatabase::Push {
+                key: "foo".into(),
+                value: Value::Bytes(vec![1, 2, 3]).into(),
+                action: Action::Append,
+                ttl: None,
+            },
+        };
+        let mut bytes = Vec::new();
+        ciborium::ser::into_writer(&request, &mut bytes).unwrap();
+        let decoded: Request = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(request, decoded);
+    }
+
+    #[test]
+    fn test_response_request_id() {
+        let db = crate::Database::new();
+        let conn = db.connect(|_| ());
+
+        let request = |value: i64, request_id: &str| Request {
+            request_id: Some(request_id.to_string()),
+            message: MessageToDatabase::Push {
+                key: "foo".into(),
+                value: Value::Integer(value.into()).into(),
+                action: Action::ReplaceIf {
+                    expected_seq: SequenceNumber(0),
+                },
+                ttl: None,
+            },
+        };
+        assert_eq!(None, conn.send_request(&request(1, "a")).unwrap());
+
+        // A conflict built by the database echoes the id of the push.
+        assert!(matches!(
+            conn.send_request(&request(2, "b")).unwrap(),
+            Some(MessageFromDatabase::Conflict { request_id: Some(id), .. }) if id == "b"
+        ));
+
+        // So does every error in the response to a batch.
+        let batch = Request {
+            request_id: Some("c".to_string()),
+            message: MessageToDatabase::Batch {
+                ops: vec![PushOp {
+                    key: "foo".into(),
+                    value: Value::Integer(3.into()).into(),
+                    action: Action::Compact {
+                        seq: SequenceNumber(5),
+                    },
+                    ttl: None,
+                }],
+                atomic: false,
+            },
+        };
+        assert_eq!(
+            Some(MessageFromDatabase::Batch {
+                messages: vec![Error::Conflict(
+                    "foo".into(),
+                    "5 is after the most recent value, 1".into()
+                )
+                .to_message(Some("c".to_string()))]
+            }),
+            conn.send_request(&batch).unwrap()
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use crate::types::MessageFromDatabase;
+use std::{
+    fmt::Display,
+    sync::{Arc, OnceLock},
+};
+
+/// How messages are encoded for a client.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Json,
+    Cbor,
+}
+
+#[derive(Debug)]
+pub struct EncodeError(String);
+
+impl Display for EncodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Could not encode message: {}", self.0)
+    }
+}
+
+impl std::error::Error for EncodeError {}
+
+/// A message from the database, shared by every connection it is delivered
+/// to. Each encoding of the message is computed at most once, by the first
+/// connection which asks for it.
+#[derive(Debug)]
+pub struct Frame {
+    message: MessageFromDatabase,
+    json: OnceLock<Arc<[u8]>>,
+    cbor: OnceLock<Arc<[u8]>>,
+}
+
+impl Frame {
+    pub fn new(message: MessageFromDatabase) -> Self {
+        Frame {
+            message,
+            json: OnceLock::new(),
+            cbor: OnceLock::new(),
+        }
+    }
+
+    pub fn message(&self) -> &MessageFromDatabase {
+        &self.message
+    }
+
+    /// The message, encoded as JSON text or as CBOR.
+    pub fn encode(&self, encoding: Encoding) -> Result<Arc<[u8]>, EncodeError> {
+        let cell = match encoding {
+            Encoding::Json => &self.json,
+            Encoding::Cbor => &self.cbor,
+        };
+
+        if let Some(encoded) = cell.get() {
+            return Ok(encoded.clone());
+        }
+
+        let encoded: Arc<[u8]> = match encoding {
+            Encoding::Json => serde_json::to_vec(&self.message)
+                .map_err(|e| EncodeError(e.to_string()))?
+                .into(),
+            Encoding::Cbor => {
+                let mut buffer = Vec::new();
+                ciborium::ser::into_writer(&self.message, &mut buffer)
+                    .map_err(|e| EncodeError(e.to_string()))?;
+                buffer.into()
+            }
+        };
+
+        // Another thread may have encoded the message in the meantime, in
+        // which case either encoding will do.
+        Ok(cell.get_or_init(|| encoded).clone())
+    }
+}
+
+impl From<MessageFromDatabase> for Frame {
+    fn from(message: MessageFromDatabase) -> Self {
+        Frame::new(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encode_once() {
+        let frame = Frame::new(MessageFromDatabase::Pong { nonce: Some(1) });
+
+        let json = frame.encode(Encoding::Json).unwrap();
+        assert_eq!(br#"{"type":"pong","nonce":1}"#, &json[..]);
+        assert!(Arc::ptr_eq(&json, &frame.encode(Encoding::Json).unwrap()));
+
+        let cbor = frame.encode(Encoding::Cbor).unwrap();
+        let decoded: MessageFromDatabase = ciborium::de::from_reader(&cbor[..]).unwrap();
+        assert_eq!(frame.message(), &decoded);
+    }
+}
@@ -1,13 +1,26 @@
 #![doc = include_str!("../README.md")]
 
+mod backend;
 mod connection;
 mod db;
+mod dispatch;
+mod error;
+mod frame;
+pub mod snapshot;
 mod store;
+#[cfg(feature = "futures")]
+mod stream;
 
 #[cfg(test)]
 mod tests;
 pub mod types;
 
+pub use backend::{Expirations, MemoryBackend, StorageBackend, StorageError};
 pub use db::Database;
-pub use store::{ApplyResult, DeleteInstruction, PushInstruction, Store, ValueLog};
-pub use types::{Key, MessageFromDatabase, MessageToDatabase};
+pub use error::Error;
+pub use frame::{EncodeError, Encoding, Frame};
+pub use snapshot::{Snapshot, SnapshotError};
+pub use store::{ApplyResult, DeleteInstruction, PushInstruction, ReadRange, Store, ValueLog};
+#[cfg(feature = "futures")]
+pub use stream::{ConnectionSink, ConnectionStream};
+pub use types::{ErrorCode, Key, MessageFromDatabase, MessageToDatabase, Request};
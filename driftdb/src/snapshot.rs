@@ -0,0 +1,338 @@
+use crate::{
+    backend::StorageError,
+    types::{Key, SequenceNumber, SequenceValue},
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, fmt::Display};
+
+/// Version of the snapshot format written by this crate.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+/// A complete, self-contained copy of a room.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Snapshot {
+    /// Version of the snapshot format.
+    pub version: u32,
+
+    /// The room's sequence counter at the time of the snapshot.
+    pub sequence_number: SequenceNumber,
+
+    /// Every key in the room, in key order.
+    pub keys: Vec<KeySnapshot>,
+}
+
+/// The stream of a single key within a [`Snapshot`].
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct KeySnapshot {
+    pub key: Key,
+
+    /// The sequence number the stream was compacted through, if it has been
+    /// compacted. The compacted value itself may have been trimmed since.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compacted_through: Option<SequenceNumber>,
+
+    pub data: Vec<SequenceValue>,
+
+    /// Default time-to-live, in milliseconds, of values pushed to the key.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+
+    /// Expiry time of every value in `data` which has one.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub expirations: Vec<ValueExpiry>,
+}
+
+/// The time at which a value of a [`KeySnapshot`] expires.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Deserialize, Serialize)]
+pub struct ValueExpiry {
+    pub seq: SequenceNumber,
+
+    /// Milliseconds since the Unix epoch.
+    pub expires_at: u64,
+}
+
+/// The first line of a snapshot in NDJSON format. Each following line is a
+/// [`KeySnapshot`].
+#[derive(Deserialize, Serialize)]
+struct NdjsonHeader {
+    version: u32,
+    sequence_number: SequenceNumber,
+}
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    /// The snapshot could not be encoded or decoded.
+    Format(String),
+
+    /// The snapshot was written by a newer, incompatible version.
+    UnsupportedVersion(u32),
+
+    /// The snapshot is well-formed, but its contents are inconsistent.
+    Invalid(String),
+
+    /// The snapshot could not be restored because the room's storage failed.
+    /// The room may have been partially restored.
+    Storage(StorageError),
+}
+
+impl Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Format(message) => write!(f, "Invalid snapshot: {}", message),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "Unsupported snapshot version: {}", version)
+            }
+            SnapshotError::Invalid(message) => write!(f, "Invalid snapshot: {}", message),
+            SnapshotError::Storage(err) => write!(f, "Could not restore snapshot: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+fn check_version(version: u32) -> Result<(), SnapshotError> {
+    if version > SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    Ok(())
+}
+
+impl Snapshot {
+    /// Check that every key appears once, that each stream is in strictly
+    /// increasing sequence order, that no sequence number is greater than the
+    /// snapshot's sequence counter, and that expiry times are only given for
+    /// values in the snapshot.
+    pub fn validate(&self) -> Result<(), SnapshotError> {
+        let mut keys = HashSet::new();
+
+        for key_snapshot in &self.keys {
+            let key = &key_snapshot.key;
+            if !keys.insert(key) {
+                return Err(SnapshotError::Invalid(format!("duplicate key {}", key)));
+            }
+
+            let mut last_seq = key_snapshot.compacted_through;
+            if let (Some(compacted_through), Some(first)) =
+                (key_snapshot.compacted_through, key_snapshot.data.first())
+            {
+                // The compacted value itself may have been trimmed since.
+                if compacted_through > first.seq {
+                    return Err(SnapshotError::Invalid(format!(
+                        "{} is compacted through {}, after its first value {}",
+                        key, compacted_through.0, first.seq.0
+                    )));
+                }
+                last_seq = None;
+            }
+
+            for value in &key_snapshot.data {
+                if last_seq.is_some_and(|last_seq| value.seq <= last_seq) {
+                    return Err(SnapshotError::Invalid(format!(
+                        "values of {} are not in increasing sequence order",
+                        key
+                    )));
+                }
+                last_seq = Some(value.seq);
+            }
+
+            if let Some(last_seq) = last_seq {
+                if last_seq > self.sequence_number {
+                    return Err(SnapshotError::Invalid(format!(
+                        "{} has sequence number {}, after the snapshot's {}",
+                        key, last_seq.0, self.sequence_number.0
+                    )));
+                }
+            }
+
+            for expiry in &key_snapshot.expirations {
+                if key_snapshot
+                    .data
+                    .binary_search_by_key(&expiry.seq, |value| value.seq)
+                    .is_err()
+                {
+                    return Err(SnapshotError::Invalid(format!(
+                        "{} has an expiry time for missing value {}",
+                        key, expiry.seq.0
+                    )));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn to_cbor(&self) -> Result<Vec<u8>, SnapshotError> {
+        let mut buffer = Vec::new();
+        ciborium::ser::into_writer(self, &mut buffer)
+            .map_err(|e| SnapshotError::Format(e.to_string()))?;
+
+        Ok(buffer)
+    }
+
+    pub fn from_cbor(bytes: &[u8]) -> Result<Self, SnapshotError> {
+        let snapshot: Snapshot =
+            ciborium::de::from_reader(bytes).map_err(|e| SnapshotError::Format(e.to_string()))?;
+        check_version(snapshot.version)?;
+
+        Ok(snapshot)
+    }
+
+    /// Encode as newline-delimited JSON: a header line with the version and
+    /// sequence number, followed by one line per key.
+    pub fn to_ndjson(&self) -> Result<String, SnapshotError> {
+        let header = NdjsonHeader {
+            version: self.version,
+            sequence_number: self.sequence_number,
+        };
+
+        let mut lines = vec![serde_json::to_string(&header)];
+        lines.extend(self.keys.iter().map(serde_json::to_string));
+
+        let mut result = lines
+            .into_iter()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| SnapshotError::Format(e.to_string()))?
+            .join("\n");
+        result.push('\n');
+
+        Ok(result)
+    }
+
+    pub fn from_ndjson(text: &str) -> Result<Self, SnapshotError> {
+        let mut lines = text.lines().filter(|line| !line.trim().is_empty());
+
+        let header = lines
+            .next()
+            .ok_or_else(|| SnapshotError::Format("missing header line".to_string()))?;
+        let header: NdjsonHeader =
+            serde_json::from_str(header).map_err(|e| SnapshotError::Format(e.to_string()))?;
+        check_version(header.version)?;
+
+        let keys = lines
+            .map(serde_json::from_str)
+            .collect::<Result<Vec<KeySnapshot>, _>>()
+            .map_err(|e| SnapshotError::Format(e.to_string()))?;
+
+        Ok(Snapshot {
+            version: header.version,
+            sequence_number: header.sequence_number,
+            keys,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ciborium::Value;
+
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            sequence_number: SequenceNumber(7),
+            keys: vec![
+                KeySnapshot {
+                    key: "bar".into(),
+                    compacted_through: None,
+                    data: vec![SequenceValue {
+                        value: Value::Text("baz".to_string()).into(),
+                        seq: SequenceNumber(7),
+                    }],
+                    ttl: Some(1_000),
+                    expirations: vec![ValueExpiry {
+                        seq: SequenceNumber(7),
+                        expires_at: 2_000,
+                    }],
+                },
+                KeySnapshot {
+                    key: "foo".into(),
+                    compacted_through: Some(SequenceNumber(4)),
+                    data: vec![
+                        SequenceValue {
+                            value: Value::Integer(4.into()).into(),
+                            seq: SequenceNumber(4),
+                        },
+                        SequenceValue {
+                            value: Value::Integer(5.into()).into(),
+                            seq: SequenceNumber(5),
+                        },
+                    ],
+                    ttl: None,
+                    expirations: vec![],
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_cbor_round_trip() {
+        let snapshot = snapshot();
+        let bytes = snapshot.to_cbor().unwrap();
+        assert_eq!(snapshot, Snapshot::from_cbor(&bytes).unwrap());
+    }
+
+    #[test]
+    fn test_ndjson_round_trip() {
+        let snapshot = snapshot();
+        let text = snapshot.to_ndjson().unwrap();
+        assert_eq!(3, text.lines().count());
+        assert_eq!(snapshot, Snapshot::from_ndjson(&text).unwrap());
+    }
+
+    #[test]
+    fn test_reject_newer_version() {
+        let mut snapshot = snapshot();
+        snapshot.version = SNAPSHOT_VERSION + 1;
+        let text = snapshot.to_ndjson().unwrap();
+
+        assert!(matches!(
+            Snapshot::from_ndjson(&text),
+            Err(SnapshotError::UnsupportedVersion(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(snapshot().validate().is_ok());
+
+        let mut duplicate = snapshot();
+        duplicate.keys.push(duplicate.keys[0].clone());
+        assert!(matches!(
+            duplicate.validate(),
+            Err(SnapshotError::Invalid(_))
+        ));
+
+        let mut unsorted = snapshot();
+        unsorted.keys[1].data.reverse();
+        assert!(matches!(
+            unsorted.validate(),
+            Err(SnapshotError::Invalid(_))
+        ));
+
+        let mut repeated = snapshot();
+        repeated.keys[1].data[1].seq = SequenceNumber(4);
+        assert!(matches!(
+            repeated.validate(),
+            Err(SnapshotError::Invalid(_))
+        ));
+
+        let mut ahead = snapshot();
+        ahead.sequence_number = SequenceNumber(6);
+        assert!(matches!(ahead.validate(), Err(SnapshotError::Invalid(_))));
+
+        let mut missing_expiry = snapshot();
+        missing_expiry.keys[0].expirations[0].seq = SequenceNumber(6);
+        assert!(matches!(
+            missing_expiry.validate(),
+            Err(SnapshotError::Invalid(_))
+        ));
+
+        let mut compacted_after_first = snapshot();
+        compacted_after_first.keys[1].compacted_through = Some(SequenceNumber(5));
+        assert!(matches!(
+            compacted_after_first.validate(),
+            Err(SnapshotError::Invalid(_))
+        ));
+    }
+}
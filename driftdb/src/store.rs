@@ -1,19 +1,114 @@
-use crate::types::{Action, Key, SequenceNumber, SequenceValue};
+use crate::{
+    backend::{Expirations, MemoryBackend, StorageBackend, StorageError},
+    error::Error,
+    snapshot::{KeySnapshot, Snapshot, SnapshotError, ValueExpiry, SNAPSHOT_VERSION},
+    types::{Action, Key, KeyInfo, SequenceNumber, SequenceValue},
+};
 use ciborium::value::Value;
-use std::collections::{HashMap, VecDeque};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    ops::Bound,
+    sync::Arc,
+};
 
+/// The values retained for a key, always sorted by sequence number, so that
+/// reads and trims can binary search instead of scanning the whole stream.
 #[derive(Default)]
 pub struct ValueLog {
-    pub values: VecDeque<SequenceValue>,
+    values: VecDeque<SequenceValue>,
+
+    /// The highest sequence number the stream has been compacted through.
+    /// Compactions only ever move this forward, and it is kept even once the
+    /// compacted value itself has been trimmed.
+    pub compacted_through: Option<SequenceNumber>,
 }
 
-#[derive(Default)]
+impl ValueLog {
+    pub fn values(&self) -> &VecDeque<SequenceValue> {
+        &self.values
+    }
+
+    /// Position of the first value with a sequence number greater than `seq`.
+    fn index_after(&self, seq: SequenceNumber) -> usize {
+        self.values.partition_point(|v| v.seq <= seq)
+    }
+
+    /// Add a value in sequence order. Values normally arrive in order, in
+    /// which case this is a push to the end of the log.
+    pub fn insert(&mut self, value: SequenceValue) {
+        match self.values.back() {
+            Some(last) if last.seq >= value.seq => {
+                let index = self.index_after(value.seq);
+                self.values.insert(index, value);
+            }
+            _ => self.values.push_back(value),
+        }
+    }
+
+    /// Add the result of a compaction, which becomes the first value once
+    /// the values it replaces have been removed with [`ValueLog::delete_up_to`].
+    pub fn push_front(&mut self, value: SequenceValue) {
+        self.compacted_through = Some(value.seq);
+        match self.values.front() {
+            Some(first) if first.seq <= value.seq => self.insert(value),
+            _ => self.values.push_front(value),
+        }
+    }
+
+    /// Remove every value with a sequence number up to and including `seq`.
+    pub fn delete_up_to(&mut self, seq: SequenceNumber) {
+        let index = self.index_after(seq);
+        self.values.drain(..index);
+    }
+
+    /// The values within `range`, most recent first if the range is reversed.
+    pub fn get_range(&self, range: &ReadRange) -> Vec<SequenceValue> {
+        let start = self.index_after(range.after);
+        let end = match range.until {
+            Some(until) => self.index_after(until).max(start),
+            None => self.values.len(),
+        };
+
+        let values = self.values.range(start..end);
+        let limit = range.limit.unwrap_or(usize::MAX);
+        if range.reverse {
+            values.rev().take(limit).cloned().collect()
+        } else {
+            values.take(limit).cloned().collect()
+        }
+    }
+}
+
+/// Sequence numbers are reserved from the backend in blocks of this size, so
+/// that the counter survives a restart without a write for every message.
+const SEQUENCE_RESERVATION: u64 = 1000;
+
 pub struct Store {
-    subjects: HashMap<Key, ValueLog>,
+    backend: Box<dyn StorageBackend>,
     sequence_number: SequenceNumber,
+
+    /// The highest sequence number reserved from the backend.
+    reserved_sequence: SequenceNumber,
+
+    /// Every key which has a stream, in key order, so that keys can be listed
+    /// without sorting all of them.
+    keys: BTreeSet<Key>,
+
+    /// Default time-to-live, in milliseconds, of values pushed to each key.
+    key_ttls: HashMap<Key, u64>,
+
+    /// Expiry time, in milliseconds since the Unix epoch, of values which have one.
+    expirations: HashMap<Key, BTreeMap<SequenceNumber, u64>>,
+}
+
+impl Default for Store {
+    fn default() -> Self {
+        Self::with_backend(MemoryBackend::default())
+    }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum DeleteInstruction {
     /// Delete all values for the given subject.
     Delete,
@@ -22,7 +117,7 @@ pub enum DeleteInstruction {
     DeleteUpTo(SequenceNumber),
 }
 
-#[derive(Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum PushInstruction {
     /// Push the given value to the end of the subject.
     Push(SequenceValue),
@@ -31,7 +126,33 @@ pub enum PushInstruction {
     PushStart(SequenceValue),
 }
 
-#[derive(Clone)]
+/// A bounded read of the stream of a key.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReadRange {
+    /// Only read values with a sequence number greater than this.
+    pub after: SequenceNumber,
+
+    /// Only read values with a sequence number less than or equal to this.
+    pub until: Option<SequenceNumber>,
+
+    /// Maximum number of values to read.
+    pub limit: Option<usize>,
+
+    /// Read the most recent values first.
+    pub reverse: bool,
+}
+
+impl ReadRange {
+    pub fn contains(&self, seq: SequenceNumber) -> bool {
+        seq > self.after && self.until.map(|until| seq <= until).unwrap_or(true)
+    }
+}
+
+/// The outcome of applying an action to a [`Store`].
+///
+/// Only the key and the instructions are serialized, which is everything a
+/// replica needs to reproduce the change.
+#[derive(Clone, Serialize, Deserialize)]
 pub struct ApplyResult {
     pub key: Key,
 
@@ -42,55 +163,566 @@ pub struct ApplyResult {
     pub push_instruction: Option<PushInstruction>,
 
     /// Optional value to broadcast to clients.
+    #[serde(skip)]
     pub broadcast: Option<SequenceValue>,
 
     /// The number of retained records for the given subject after applying the action.
+    #[serde(skip)]
     pub stream_size: usize,
+
+    /// If the key was deleted, the sequence number of the tombstone to
+    /// broadcast to clients.
+    #[serde(skip)]
+    pub tombstone: Option<SequenceNumber>,
+
+    /// Time at which the pushed value expires, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<u64>,
+
+    /// Compaction watermark to set for the subject without pushing a compacted
+    /// value, which is only needed when a snapshot is restored whose compacted
+    /// value has since been trimmed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub compacted_through: Option<SequenceNumber>,
 }
 
 impl ApplyResult {
     pub fn mutates(&self) -> bool {
-        self.delete_instruction.is_some() || self.push_instruction.is_some()
+        self.delete_instruction.is_some()
+            || self.push_instruction.is_some()
+            || self.compacted_through.is_some()
+    }
+
+    /// If this result compacts the stream, the sequence number it was
+    /// compacted through.
+    pub fn compaction(&self) -> Option<SequenceNumber> {
+        match &self.push_instruction {
+            Some(PushInstruction::PushStart(value)) => Some(value.seq),
+            _ => None,
+        }
+    }
+
+    /// The sequence number of the value this result pushes, if any.
+    pub fn pushed_seq(&self) -> Option<SequenceNumber> {
+        match &self.push_instruction {
+            Some(PushInstruction::Push(value) | PushInstruction::PushStart(value)) => {
+                Some(value.seq)
+            }
+            None => None,
+        }
+    }
+
+    /// Apply the delete and push instructions of this result, the expiry time
+    /// of the pushed value and the compaction watermark to a storage backend.
+    pub fn apply_to<B: StorageBackend + ?Sized>(&self, backend: &mut B) {
+        match &self.delete_instruction {
+            Some(DeleteInstruction::Delete) => backend.delete(&self.key),
+            Some(DeleteInstruction::DeleteUpTo(seq)) => backend.delete_up_to(&self.key, *seq),
+            None => {}
+        }
+
+        if let Some(seq) = self.compacted_through {
+            backend.set_compacted_through(&self.key, seq);
+        }
+
+        match &self.push_instruction {
+            Some(PushInstruction::Push(value)) => backend.append(&self.key, value.clone()),
+            Some(PushInstruction::PushStart(value)) => backend.push_front(&self.key, value.clone()),
+            None => {}
+        }
+
+        if let (Some(expires_at), Some(seq)) = (self.expires_at, self.pushed_seq()) {
+            backend.expire(&self.key, seq, expires_at);
+        }
     }
 }
 
 impl Store {
     pub fn new(subjects: HashMap<Key, ValueLog>, sequence_number: SequenceNumber) -> Self {
+        let backend = MemoryBackend::new(subjects);
+
+        Self {
+            keys: backend.keys().into_iter().collect(),
+            backend: Box::new(backend),
+            sequence_number,
+            reserved_sequence: sequence_number,
+            key_ttls: HashMap::new(),
+            expirations: HashMap::new(),
+        }
+    }
+
+    /// Create a store on top of the given backend, resuming the sequence number
+    /// after the highest one the backend has stored or reserved, and the
+    /// time-to-lives and expiry times it has persisted.
+    pub fn with_backend<B>(mut backend: B) -> Self
+    where
+        B: StorageBackend + 'static,
+    {
+        let sequence_number = backend.load();
+        let Expirations { key_ttls, values } = backend.load_expirations();
+
         Self {
-            subjects,
+            keys: backend.keys().into_iter().collect(),
+            backend: Box::new(backend),
             sequence_number,
+            reserved_sequence: sequence_number,
+            key_ttls,
+            expirations: values,
+        }
+    }
+
+    /// An in-memory copy of the streams of `keys`, continuing from the same
+    /// sequence number, to try changes out on without applying them.
+    pub fn scratch<'a>(&self, keys: impl IntoIterator<Item = &'a Key>) -> Store {
+        let subjects = keys
+            .into_iter()
+            .map(|key| {
+                let mut log = ValueLog::default();
+                for value in self.backend.get(key, SequenceNumber::default()) {
+                    log.insert(value);
+                }
+                log.compacted_through = self.backend.compacted_through(key);
+                (key.clone(), log)
+            })
+            .collect();
+
+        let mut store = Store::new(subjects, self.sequence_number);
+        for key in store.backend.keys() {
+            if let Some(ttl) = self.key_ttl(&key) {
+                store.key_ttls.insert(key.clone(), ttl);
+            }
+            if let Some(expirations) = self.expirations.get(&key) {
+                store.expirations.insert(key, expirations.clone());
+            }
         }
+        store
     }
 
-    fn next_seq(&mut self) -> SequenceNumber {
+    fn next_seq(&mut self) -> Result<SequenceNumber, StorageError> {
         self.sequence_number.0 += 1;
-        self.sequence_number
+        self.reserve_sequence()?;
+        Ok(self.sequence_number)
+    }
+
+    /// Reserve another block of sequence numbers from the backend if the
+    /// counter has passed the last reservation.
+    fn reserve_sequence(&mut self) -> Result<(), StorageError> {
+        if self.sequence_number > self.reserved_sequence {
+            let reserved = SequenceNumber(self.sequence_number.0 + SEQUENCE_RESERVATION);
+            self.backend.reserve_sequence(reserved)?;
+            self.reserved_sequence = reserved;
+        }
+        Ok(())
     }
 
     pub fn dump(&self) -> HashMap<Key, Vec<SequenceValue>> {
-        self.subjects
-            .iter()
-            .map(|(k, v)| (k.clone(), v.values.iter().cloned().collect()))
+        self.backend
+            .keys()
+            .into_iter()
+            .map(|k| {
+                let values = self.backend.get(&k, SequenceNumber::default());
+                (k, values)
+            })
             .collect()
     }
 
+    /// Every key which starts with `prefix` and has a stream, in key order.
+    pub fn keys_with_prefix(&self, prefix: &str) -> Vec<Key> {
+        self.keys_after(prefix, None).cloned().collect()
+    }
+
+    /// The keys which start with `prefix` and come after `cursor`, in key order.
+    fn keys_after<'a>(
+        &'a self,
+        prefix: &'a str,
+        cursor: Option<&Key>,
+    ) -> impl Iterator<Item = &'a Key> + 'a {
+        let start = match cursor {
+            Some(cursor) if cursor.as_str() >= prefix => Bound::Excluded(cursor.clone()),
+            _ => Bound::Included(Key::from(prefix)),
+        };
+
+        self.keys
+            .range((start, Bound::Unbounded))
+            .take_while(move |key| key.as_str().starts_with(prefix))
+    }
+
+    /// Read the values of `key` within `range`. If the range is limited and
+    /// more values remain, also returns the cursor from which to continue: the
+    /// new lower bound, or the new upper bound when reading in reverse.
+    pub fn get_range(
+        &self,
+        key: &Key,
+        range: &ReadRange,
+    ) -> (Vec<SequenceValue>, Option<SequenceNumber>) {
+        let Some(limit) = range.limit else {
+            return (self.backend.get_range(key, range), None);
+        };
+
+        // Read one extra value to find out whether any remain.
+        let mut values = self.backend.get_range(
+            key,
+            &ReadRange {
+                limit: Some(limit.saturating_add(1)),
+                ..range.clone()
+            },
+        );
+
+        if values.len() <= limit {
+            return (values, None);
+        }
+
+        let cursor = match (
+            limit.checked_sub(1).map(|last| values[last].seq),
+            range.reverse,
+        ) {
+            (Some(last), false) => last,
+            (Some(last), true) => SequenceNumber(last.0.saturating_sub(1)),
+            // With a limit of zero, nothing is returned, so the client should
+            // continue from where it started: the lower bound, or the most
+            // recent value within range when reading in reverse.
+            (None, false) => range.after,
+            (None, true) => values[0].seq,
+        };
+        values.truncate(limit);
+
+        (values, Some(cursor))
+    }
+
+    /// List up to `limit` keys which start with `prefix` and come after
+    /// `cursor`, in key order. If more keys remain, also returns the cursor
+    /// from which to continue. At least one key is listed if any remain, even
+    /// if `limit` is zero.
+    pub fn list_keys(
+        &self,
+        prefix: &str,
+        cursor: Option<&Key>,
+        limit: usize,
+    ) -> (Vec<KeyInfo>, Option<Key>) {
+        let mut keys = self.keys_after(prefix, cursor);
+        let page: Vec<&Key> = keys.by_ref().take(limit.max(1)).collect();
+
+        let next_cursor = match keys.next() {
+            Some(_) => page.last().map(|key| (*key).clone()),
+            None => None,
+        };
+
+        let keys = page
+            .into_iter()
+            .map(|key| KeyInfo {
+                size: self.backend.len(key),
+                latest_seq: self.latest_seq(key).unwrap_or_default(),
+                key: key.clone(),
+            })
+            .collect();
+
+        (keys, next_cursor)
+    }
+
     pub fn get(&self, key: &Key, min_sequence: SequenceNumber) -> Vec<SequenceValue> {
-        let Some(log) = self.subjects
-            .get(key) else {
-                return vec![];
-            };
+        self.backend.get(key, min_sequence)
+    }
+
+    pub fn flush(&mut self) {
+        self.backend.flush();
+    }
+
+    /// Capture every key, the sequence counter, the compaction markers, and
+    /// the time-to-lives and expiry times.
+    pub fn snapshot(&self) -> Snapshot {
+        // A key's time-to-live outlives its values until the key is deleted.
+        let mut keys = self.backend.keys();
+        keys.extend(self.key_ttls.keys().cloned());
+        keys.sort();
+        keys.dedup();
+
+        let keys = keys
+            .into_iter()
+            .map(|key| KeySnapshot {
+                compacted_through: self.backend.compacted_through(&key),
+                data: self.backend.get(&key, SequenceNumber::default()),
+                ttl: self.key_ttl(&key),
+                expirations: self
+                    .expirations
+                    .get(&key)
+                    .into_iter()
+                    .flatten()
+                    .map(|(seq, expires_at)| ValueExpiry {
+                        seq: *seq,
+                        expires_at: *expires_at,
+                    })
+                    .collect(),
+                key,
+            })
+            .collect();
+
+        Snapshot {
+            version: SNAPSHOT_VERSION,
+            sequence_number: self.sequence_number,
+            keys,
+        }
+    }
+
+    /// Replace the entire contents of the store with the given snapshot.
+    ///
+    /// Returns the instructions which were applied to the backend, so that they
+    /// can be forwarded to replicas. The sequence counter never moves backwards,
+    /// so sequence numbers already handed out are not reused. A snapshot which
+    /// fails [`Snapshot::validate`] is rejected before anything is replaced.
+    pub fn restore(&mut self, snapshot: Snapshot) -> Result<Vec<ApplyResult>, SnapshotError> {
+        snapshot.validate()?;
+
+        let instruction = |key: &Key, delete_instruction, push_instruction| ApplyResult {
+            key: key.clone(),
+            delete_instruction,
+            push_instruction,
+            broadcast: None,
+            stream_size: 0,
+            tombstone: None,
+            expires_at: None,
+            compacted_through: None,
+        };
 
-        log.values
+        let mut results: Vec<ApplyResult> = self
+            .backend
+            .keys()
             .iter()
-            .filter(|d| d.seq > min_sequence)
+            .map(|key| instruction(key, Some(DeleteInstruction::Delete), None))
+            .collect();
+
+        let mut key_ttls = HashMap::new();
+        for key_snapshot in snapshot.keys {
+            if let Some(ttl) = key_snapshot.ttl {
+                key_ttls.insert(key_snapshot.key.clone(), ttl);
+            }
+
+            let expirations: HashMap<SequenceNumber, u64> = key_snapshot
+                .expirations
+                .iter()
+                .map(|expiry| (expiry.seq, expiry.expires_at))
+                .collect();
+
+            // If the compacted value has since been trimmed, the watermark is
+            // set on its own, since no value can be pushed to the start.
+            let first_seq = key_snapshot.data.first().map(|value| value.seq);
+            if let (Some(compacted_through), Some(first_seq)) =
+                (key_snapshot.compacted_through, first_seq)
+            {
+                if compacted_through < first_seq {
+                    let mut result = instruction(&key_snapshot.key, None, None);
+                    result.compacted_through = Some(compacted_through);
+                    results.push(result);
+                }
+            }
+
+            for (i, value) in key_snapshot.data.into_iter().enumerate() {
+                let expires_at = expirations.get(&value.seq).copied();
+                let push_instruction =
+                    if i == 0 && key_snapshot.compacted_through == Some(value.seq) {
+                        PushInstruction::PushStart(value)
+                    } else {
+                        PushInstruction::Push(value)
+                    };
+
+                let mut result = instruction(&key_snapshot.key, None, Some(push_instruction));
+                result.expires_at = expires_at;
+                results.push(result);
+            }
+        }
+
+        let stale_ttls: Vec<Key> = self
+            .key_ttls
+            .keys()
+            .filter(|key| !key_ttls.contains_key(*key))
             .cloned()
-            .collect()
+            .collect();
+        for key in stale_ttls {
+            self.set_key_ttl(&key, None)
+                .map_err(SnapshotError::Storage)?;
+        }
+        for (key, ttl) in key_ttls {
+            self.set_key_ttl(&key, Some(ttl))
+                .map_err(SnapshotError::Storage)?;
+        }
+
+        self.expirations.clear();
+        for result in &mut results {
+            self.apply_instructions(result)
+                .map_err(SnapshotError::Storage)?;
+        }
+
+        self.sequence_number = self.sequence_number.max(snapshot.sequence_number);
+        self.reserve_sequence().map_err(SnapshotError::Storage)?;
+
+        Ok(results)
+    }
+
+    /// The sequence number of the most recent value retained for `key`.
+    fn latest_seq(&self, key: &Key) -> Option<SequenceNumber> {
+        let len = self.backend.len(key);
+        self.backend.seq_at(key, len.checked_sub(1)?)
+    }
+
+    /// The most recent value retained for `key`.
+    pub fn latest(&self, key: &Key) -> Option<SequenceValue> {
+        let last = self.latest_seq(key)?;
+        self.backend
+            .get(key, SequenceNumber(last.0.saturating_sub(1)))
+            .pop()
+    }
+
+    /// The number of values retained for `key`.
+    pub fn stream_size(&self, key: &Key) -> usize {
+        self.backend.len(key)
+    }
+
+    /// The highest sequence number the stream for `key` has been compacted
+    /// through, if it has been compacted.
+    pub fn compacted_through(&self, key: &Key) -> Option<SequenceNumber> {
+        self.backend.compacted_through(key)
+    }
+
+    /// Check that compacting `key` through `seq` moves its compaction watermark
+    /// forward, covers no less than the oldest value retained, and does not
+    /// pass its most recent value. If not, returns why.
+    pub fn check_compaction(&self, key: &Key, seq: SequenceNumber) -> Result<(), String> {
+        if let Some(watermark) = self.backend.compacted_through(key) {
+            if seq <= watermark {
+                return Err(format!("already compacted through {}", watermark.0));
+            }
+        }
+
+        // Values before the oldest one retained were replaced or trimmed, so a
+        // compaction through them would put a stale value in front of newer ones.
+        if let Some(first) = self.backend.seq_at(key, 0) {
+            if seq < first {
+                return Err(format!(
+                    "{} is before the oldest value retained, {}",
+                    seq.0, first.0
+                ));
+            }
+        }
+
+        match self.latest_seq(key) {
+            Some(latest) if seq <= latest => Ok(()),
+            Some(latest) => Err(format!(
+                "{} is after the most recent value, {}",
+                seq.0, latest.0
+            )),
+            None => Err("the stream is empty".to_string()),
+        }
+    }
+
+    /// Check that the most recent value retained for `key` has the sequence
+    /// number `expected_seq`, or that the stream is empty if it is zero. If
+    /// not, returns the most recent value.
+    pub fn check_latest_seq(
+        &self,
+        key: &Key,
+        expected_seq: SequenceNumber,
+    ) -> Result<(), Option<SequenceValue>> {
+        if self.latest_seq(key).unwrap_or_default() == expected_seq {
+            Ok(())
+        } else {
+            Err(self.latest(key))
+        }
+    }
+
+    /// Check the precondition of an action: the expected sequence number of a
+    /// `ReplaceIf`, or that a `Compact` moves the compaction watermark forward.
+    /// [`Store::apply`] makes this check before applying an action.
+    pub fn check_precondition(&self, key: &Key, action: &Action) -> Result<(), Error> {
+        match action {
+            Action::ReplaceIf { expected_seq } => self
+                .check_latest_seq(key, *expected_seq)
+                .map_err(|current| Error::ReplaceConflict {
+                    key: key.clone(),
+                    expected_seq: *expected_seq,
+                    current,
+                }),
+            Action::Compact { seq } => self
+                .check_compaction(key, *seq)
+                .map_err(|reason| Error::Conflict(key.clone(), reason)),
+            _ => Ok(()),
+        }
     }
 
-    pub fn apply(&mut self, key: &Key, value: Value, action: &Action) -> ApplyResult {
+    /// Values expire in stream order, so check that an action leaves no value
+    /// which expires behind one which never does, where it would never be
+    /// removed.
+    fn check_expiry_order(
+        &self,
+        key: &Key,
+        action: &Action,
+        expires_at: Option<u64>,
+    ) -> Result<(), String> {
+        let len = self.backend.len(key);
+        let expirations = self.expirations.get(key);
+
+        match (action, expires_at) {
+            (Action::Append | Action::AppendCapped { .. }, Some(_)) => {
+                if expirations.map(BTreeMap::len).unwrap_or_default() == len {
+                    return Ok(());
+                }
+
+                let first_kept = match action {
+                    Action::AppendCapped { max_len } => (len + 1).saturating_sub((*max_len).max(1)),
+                    _ => 0,
+                };
+                let never_expires = (first_kept..len)
+                    .filter_map(|index| self.backend.seq_at(key, index))
+                    .find(|seq| !expirations.is_some_and(|e| e.contains_key(seq)));
+                match never_expires {
+                    Some(seq) => Err(format!(
+                        "{} never expires, and values expire in stream order",
+                        seq.0
+                    )),
+                    None => Ok(()),
+                }
+            }
+            (Action::Compact { seq }, None) => {
+                let expires_after = expirations
+                    .and_then(|e| e.range((Bound::Excluded(*seq), Bound::Unbounded)).next());
+                match expires_after {
+                    Some((after, _)) => Err(format!(
+                        "the compacted value never expires, but {} after it does",
+                        after.0
+                    )),
+                    None => Ok(()),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply an action to the stream of `key`. If its precondition fails, or the
+    /// backend fails to persist the change, it is not applied.
+    pub fn apply(
+        &mut self,
+        key: &Key,
+        value: impl Into<Arc<Value>>,
+        action: &Action,
+    ) -> Result<ApplyResult, Error> {
+        self.apply_with_expiry(key, value, action, None)
+    }
+
+    /// Like [`Store::apply`], but any value pushed expires at `expires_at`, in
+    /// milliseconds since the Unix epoch.
+    pub fn apply_with_expiry(
+        &mut self,
+        key: &Key,
+        value: impl Into<Arc<Value>>,
+        action: &Action,
+        expires_at: Option<u64>,
+    ) -> Result<ApplyResult, Error> {
+        self.check_precondition(key, action)?;
+        self.check_expiry_order(key, action, expires_at)
+            .map_err(|reason| Error::Conflict(key.clone(), reason))?;
+
+        let value = value.into();
         let mut result = match action {
             Action::Append => {
-                let seq = self.next_seq();
+                let seq = self.next_seq()?;
                 let value = SequenceValue { value, seq };
 
                 ApplyResult {
@@ -99,18 +731,48 @@ impl Store {
                     push_instruction: Some(PushInstruction::Push(value.clone())),
                     broadcast: Some(value),
                     stream_size: 0,
+                    tombstone: None,
+                    expires_at: None,
+                    compacted_through: None,
                 }
             }
-            Action::Replace => {
-                let seq = self.next_seq();
+            Action::AppendCapped { max_len } => {
+                let seq = self.next_seq()?;
                 let value = SequenceValue { value, seq };
 
+                // Drop enough of the oldest values to make room for the new one.
+                let excess = (self.backend.len(key) + 1).saturating_sub((*max_len).max(1));
+                let delete_instruction = excess
+                    .checked_sub(1)
+                    .and_then(|last| self.backend.seq_at(key, last))
+                    .map(DeleteInstruction::DeleteUpTo);
+
                 ApplyResult {
                     key: key.clone(),
-                    delete_instruction: Some(DeleteInstruction::Delete),
+                    delete_instruction,
+                    push_instruction: Some(PushInstruction::Push(value.clone())),
+                    broadcast: Some(value),
+                    stream_size: 0,
+                    tombstone: None,
+                    expires_at: None,
+                    compacted_through: None,
+                }
+            }
+            Action::Replace | Action::ReplaceIf { .. } => {
+                let seq = self.next_seq()?;
+                let value = SequenceValue { value, seq };
+
+                // Only the values are replaced: the key, and so its compaction
+                // watermark, stays.
+                ApplyResult {
+                    key: key.clone(),
+                    delete_instruction: self.latest_seq(key).map(DeleteInstruction::DeleteUpTo),
                     push_instruction: Some(PushInstruction::Push(value.clone())),
                     broadcast: Some(value),
                     stream_size: 0,
+                    tombstone: None,
+                    expires_at: None,
+                    compacted_through: None,
                 }
             }
             Action::Compact { seq } => ApplyResult {
@@ -122,45 +784,171 @@ impl Store {
                 })),
                 broadcast: None,
                 stream_size: 0,
+                tombstone: None,
+                expires_at: None,
+                compacted_through: None,
             },
+            Action::Delete => {
+                let seq = self.next_seq()?;
+
+                ApplyResult {
+                    key: key.clone(),
+                    delete_instruction: Some(DeleteInstruction::Delete),
+                    push_instruction: None,
+                    broadcast: None,
+                    stream_size: 0,
+                    tombstone: Some(seq),
+                    expires_at: None,
+                    compacted_through: None,
+                }
+            }
             Action::Relay => {
-                let seq = self.next_seq();
+                let seq = self.next_seq()?;
                 ApplyResult {
                     key: key.clone(),
                     delete_instruction: None,
                     push_instruction: None,
                     broadcast: Some(SequenceValue { value, seq }),
                     stream_size: 0,
+                    tombstone: None,
+                    expires_at: None,
+                    compacted_through: None,
                 }
             }
         };
 
+        if result.push_instruction.is_some() {
+            result.expires_at = expires_at;
+        }
+
+        // A deleted key loses its default time-to-live. If that can not be
+        // persisted, the deletion is rejected.
+        if result.tombstone.is_some() && self.key_ttls.contains_key(key) {
+            self.set_key_ttl(key, None)?;
+        }
+
+        self.apply_instructions(&mut result)?;
+
+        Ok(result)
+    }
+
+    /// Apply the instructions of `result` to the backend, update the expiry
+    /// times of the values it removes and pushes, and record the resulting
+    /// stream size.
+    fn apply_instructions(&mut self, result: &mut ApplyResult) -> Result<(), StorageError> {
+        self.backend.apply(result)?;
+
+        if result.delete_instruction == Some(DeleteInstruction::Delete) {
+            self.keys.remove(&result.key);
+        }
+        if result.push_instruction.is_some() && !self.keys.contains(&result.key) {
+            self.keys.insert(result.key.clone());
+        }
+
         match &result.delete_instruction {
             Some(DeleteInstruction::Delete) => {
-                let value_log = self.subjects.entry(key.clone()).or_default();
-                value_log.values.clear();
+                self.expirations.remove(&result.key);
             }
             Some(DeleteInstruction::DeleteUpTo(seq)) => {
-                let value_log = self.subjects.entry(key.clone()).or_default();
-                value_log.values.retain(|v| v.seq > *seq);
+                if let Some(expirations) = self.expirations.get_mut(&result.key) {
+                    expirations.retain(|s, _| s > seq);
+                }
             }
             None => {}
         }
 
-        match &result.push_instruction {
-            Some(PushInstruction::Push(value)) => {
-                let value_log = self.subjects.entry(key.clone()).or_default();
-                value_log.values.push_back(value.clone());
+        if let (Some(expires_at), Some(seq)) = (result.expires_at, result.pushed_seq()) {
+            self.expirations
+                .entry(result.key.clone())
+                .or_default()
+                .insert(seq, expires_at);
+        }
+
+        result.stream_size = self.backend.len(&result.key);
+        Ok(())
+    }
+
+    /// Set the default time-to-live, in milliseconds, of values pushed to `key`.
+    /// It is kept until the key is deleted.
+    pub fn set_key_ttl(&mut self, key: &Key, ttl: Option<u64>) -> Result<(), StorageError> {
+        self.backend.set_key_ttl(key, ttl)?;
+        match ttl {
+            Some(ttl) => self.key_ttls.insert(key.clone(), ttl),
+            None => self.key_ttls.remove(key),
+        };
+
+        Ok(())
+    }
+
+    pub fn key_ttl(&self, key: &Key) -> Option<u64> {
+        self.key_ttls.get(key).copied()
+    }
+
+    /// Remove expired values, returning the instructions which were applied.
+    ///
+    /// Values expire in stream order: a value is removed once it and every
+    /// value before it in the stream have expired. Pushes which would leave a
+    /// value that expires behind one that never does are rejected. Removals
+    /// which the backend fails to persist are left for the next call.
+    pub fn purge_expired(&mut self, now: u64) -> Vec<ApplyResult> {
+        let mut results = Vec::new();
+        let keys: Vec<Key> = self.expirations.keys().cloned().collect();
+
+        for key in keys {
+            let expirations = &self.expirations[&key];
+            let mut expired = 0;
+            let mut last_expired = None;
+
+            while let Some(seq) = self.backend.seq_at(&key, expired) {
+                match expirations.get(&seq) {
+                    Some(expires_at) if *expires_at <= now => {
+                        last_expired = Some(seq);
+                        expired += 1;
+                    }
+                    _ => break,
+                }
             }
-            Some(PushInstruction::PushStart(value)) => {
-                let value_log = self.subjects.entry(key.clone()).or_default();
-                value_log.values.push_front(value.clone());
+
+            let Some(last_expired) = last_expired else {
+                if expirations.is_empty() {
+                    self.expirations.remove(&key);
+                }
+                continue;
+            };
+
+            let delete_instruction = if expired == self.backend.len(&key) {
+                DeleteInstruction::Delete
+            } else {
+                DeleteInstruction::DeleteUpTo(last_expired)
+            };
+
+            let mut result = ApplyResult {
+                key,
+                delete_instruction: Some(delete_instruction),
+                push_instruction: None,
+                broadcast: None,
+                stream_size: 0,
+                tombstone: None,
+                expires_at: None,
+                compacted_through: None,
+            };
+            if self.apply_instructions(&mut result).is_ok() {
+                results.push(result);
             }
-            None => {}
         }
 
-        result.stream_size = self.subjects.get(key).map(|v| v.values.len()).unwrap_or(0);
+        results
+    }
 
-        result
+    /// The earliest time, in milliseconds since the Unix epoch, at which
+    /// [`Store::purge_expired`] will remove a value.
+    pub fn next_expiry(&self) -> Option<u64> {
+        self.expirations
+            .iter()
+            .filter_map(|(key, expirations)| {
+                let first = self.backend.seq_at(key, 0)?;
+                expirations.get(&first).copied()
+            })
+            .min()
     }
 }
@@ -0,0 +1,207 @@
+use crate::{
+    connection::Connection,
+    error::Error,
+    frame::Frame,
+    types::{MessageFromDatabase, MessageToDatabase},
+    Database,
+};
+use futures_channel::mpsc;
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::{
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+
+impl Database {
+    /// Connect to the database with an async interface instead of a callback.
+    ///
+    /// Up to `buffer` messages from the database are held for the stream. If the
+    /// stream falls further behind than that, it ends after the buffered messages,
+    /// the sink fails with [`Error::Disconnected`], and the connection is closed
+    /// once both have noticed.
+    pub fn connect_stream(&self, buffer: usize) -> (ConnectionSink, ConnectionStream) {
+        let (sender, receiver) = mpsc::channel(buffer);
+        let sender = Arc::new(Mutex::new(sender));
+
+        let connection = self.connect({
+            let sender = sender.clone();
+            move |message: &MessageFromDatabase| {
+                let mut sender = sender.lock().unwrap();
+                if let Err(err) = sender.try_send(message.clone()) {
+                    if err.is_full() {
+                        sender.close_channel();
+                    }
+                }
+            }
+        });
+
+        (
+            ConnectionSink {
+                connection: Some(connection.clone()),
+                sender,
+            },
+            ConnectionStream {
+                receiver,
+                connection: Some(connection),
+            },
+        )
+    }
+}
+
+/// Sends messages to the database. Messages which can not be handled result in
+/// an `Error` message on the [`ConnectionStream`]; the sink itself only fails
+/// once the database is gone or the stream has fallen behind.
+pub struct ConnectionSink {
+    /// Released once the stream has fallen behind.
+    connection: Option<Arc<Connection>>,
+    sender: Arc<Mutex<mpsc::Sender<MessageFromDatabase>>>,
+}
+
+impl ConnectionSink {
+    /// The connection, unless the stream has fallen behind.
+    fn connection(&mut self) -> Result<&Arc<Connection>, Error> {
+        if self.sender.lock().unwrap().is_closed() {
+            self.connection = None;
+        }
+        self.connection.as_ref().ok_or(Error::Disconnected)
+    }
+}
+
+impl Sink<MessageToDatabase> for ConnectionSink {
+    type Error = Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(self.connection().map(|_| ()))
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, message: MessageToDatabase) -> Result<(), Error> {
+        let connection = self.connection()?;
+        match connection.send_message(&message) {
+            Ok(_) => Ok(()),
+            Err(Error::DatabaseGone) => Err(Error::DatabaseGone),
+            Err(err) => {
+                (connection.callback)(&Frame::new(err.to_message(None)));
+                Ok(())
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Messages from the database, including responses to messages sent on the
+/// [`ConnectionSink`]. The connection stays open while either half is alive,
+/// until the stream falls behind.
+pub struct ConnectionStream {
+    receiver: mpsc::Receiver<MessageFromDatabase>,
+    /// Released once the stream has ended.
+    connection: Option<Arc<Connection>>,
+}
+
+impl Stream for ConnectionStream {
+    type Item = MessageFromDatabase;
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<MessageFromDatabase>> {
+        let next = Pin::new(&mut self.receiver).poll_next(cx);
+        if let Poll::Ready(None) = next {
+            self.connection = None;
+        }
+        next
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Action, ErrorCode, SequenceNumber};
+    use ciborium::Value;
+    use futures_util::{FutureExt, SinkExt, StreamExt};
+
+    fn push(key: &str) -> MessageToDatabase {
+        MessageToDatabase::Push {
+            key: key.into(),
+            value: Value::Integer(1.into()).into(),
+            action: Action::Append,
+            ttl: None,
+        }
+    }
+
+    fn get(key: &str) -> MessageToDatabase {
+        MessageToDatabase::Get {
+            key: key.into(),
+            seq: Some(SequenceNumber(0)),
+            until_seq: None,
+            limit: None,
+            reverse: false,
+            chunk_size: None,
+            prefix: false,
+            subscribe: true,
+            compact_threshold: None,
+        }
+    }
+
+    #[test]
+    fn test_connect_stream() {
+        let db = Database::new();
+        let (mut sink, mut stream) = db.connect_stream(8);
+
+        sink.send(get("foo")).now_or_never().unwrap().unwrap();
+        sink.send(push("foo")).now_or_never().unwrap().unwrap();
+        sink.send(push("")).now_or_never().unwrap().unwrap();
+
+        assert!(matches!(
+            stream.next().now_or_never(),
+            Some(Some(MessageFromDatabase::Init { .. }))
+        ));
+        assert!(matches!(
+            stream.next().now_or_never(),
+            Some(Some(MessageFromDatabase::Push { .. }))
+        ));
+        assert!(matches!(
+            stream.next().now_or_never(),
+            Some(Some(MessageFromDatabase::Error {
+                code: ErrorCode::InvalidKey,
+                ..
+            }))
+        ));
+        assert!(stream.next().now_or_never().is_none());
+    }
+
+    #[test]
+    fn test_stream_ends_when_full() {
+        let db = Database::new();
+        let (mut sink, mut stream) = db.connect_stream(1);
+
+        sink.send(get("foo")).now_or_never().unwrap().unwrap();
+        sink.send(push("foo")).now_or_never().unwrap().unwrap();
+        sink.send(push("foo")).now_or_never().unwrap().unwrap();
+        assert_eq!(
+            Some(Err(Error::Disconnected)),
+            sink.send(push("foo")).now_or_never()
+        );
+
+        // The channel holds `buffer` messages, plus one for its sender.
+        let mut messages = Vec::new();
+        while let Some(Some(message)) = stream.next().now_or_never() {
+            messages.push(message);
+        }
+        assert_eq!(2, messages.len());
+        assert!(matches!(messages[0], MessageFromDatabase::Init { .. }));
+        assert!(matches!(messages[1], MessageFromDatabase::Push { .. }));
+
+        // Both halves have released the connection, which unsubscribes it.
+        assert!(sink.connection.is_none());
+        assert!(stream.connection.is_none());
+    }
+}
@@ -1,5 +1,5 @@
 use crate::Key;
-use std::str::FromStr;
+use std::{fmt::Display, str::FromStr};
 
 use super::SequenceNumber;
 
@@ -24,9 +24,9 @@ impl KeyAndSeq {
     }
 }
 
-impl ToString for KeyAndSeq {
-    fn to_string(&self) -> String {
-        format!("{}|{}|{:020}", self.key.len(), self.key, self.seq)
+impl Display for KeyAndSeq {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}|{}|{:020}", self.key.len(), self.key, self.seq)
     }
 }
 
@@ -34,12 +34,23 @@ impl FromStr for KeyAndSeq {
     type Err = &'static str;
 
     fn from_str(s: &str) -> Result<Self, &'static str> {
-        let (key_len, rest) = s.split_at(s.find('|').unwrap());
-        let key_len = key_len.parse::<usize>().unwrap();
-        let (key, rest) = rest.split_at(key_len + 1);
-        let key = Key::new(key[1..].to_string());
-        let seq = SequenceNumber(rest[1..].parse::<u64>().unwrap());
-        Ok(Self { key, seq })
+        let (key_len, rest) = s.split_once('|').ok_or("Missing key length.")?;
+        let key_len = key_len
+            .parse::<usize>()
+            .map_err(|_| "Invalid key length.")?;
+        let key = rest
+            .get(..key_len)
+            .ok_or("Key is shorter than its length.")?;
+        let seq = rest
+            .get(key_len..)
+            .and_then(|rest| rest.strip_prefix('|'))
+            .ok_or("Missing sequence number.")?;
+        let seq = seq.parse::<u64>().map_err(|_| "Invalid sequence number.")?;
+
+        Ok(Self {
+            key: Key::new(key.to_string()),
+            seq: SequenceNumber(seq),
+        })
     }
 }
 
@@ -59,6 +70,13 @@ mod tests {
         assert_eq!(k, k2);
     }
 
+    #[test]
+    fn test_invalid_key_and_seq() {
+        assert!(KeyAndSeq::from_str("3|foo|compacted").is_err());
+        assert!(KeyAndSeq::from_str("9|foo|1").is_err());
+        assert!(KeyAndSeq::from_str("foo").is_err());
+    }
+
     #[test]
     fn test_prefix() {
         let result = KeyAndSeq::prefix_str(&Key::new("foo".to_string()));
@@ -1,10 +1,10 @@
 use ciborium::value::Value;
 use serde::{Deserialize, Serialize};
-use std::fmt::Display;
+use std::{fmt::Display, sync::Arc};
 
 pub mod key_seq_pair;
 
-#[derive(Debug, PartialEq, Eq, Clone, Serialize, Default, Deserialize, Hash)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Default, Deserialize, Hash, PartialOrd, Ord)]
 pub struct Key(String);
 
 impl Key {
@@ -16,6 +16,10 @@ impl Key {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
 }
 
 impl From<&str> for Key {
@@ -56,12 +60,28 @@ pub enum Action {
     /// Append to the stream.
     Append,
 
+    /// Append to the stream, then drop the oldest values so that at most
+    /// `max_len` values are retained. A cap of zero is treated as one, since
+    /// the appended value is always retained.
+    AppendCapped { max_len: usize },
+
     /// Replace the entire stream.
     Replace,
 
+    /// Replace the entire stream, but only if the most recent value retained
+    /// for the key has the sequence number `expected_seq`. A sequence number of
+    /// zero expects the stream to be empty. Otherwise, the sender receives a
+    /// `Conflict` message with the most recent value and nothing is broadcast.
+    ReplaceIf { expected_seq: SequenceNumber },
+
+    /// Remove the stream and the key entirely. Subscribers receive a `Deleted`
+    /// message instead of the value, which is discarded.
+    Delete,
+
     /// Replace the entire stream up to the given sequence number.
-    /// If the stream has already been rolled up to an equal or greater
-    /// sequence number, this is ignored.
+    /// If the stream has already been compacted through an equal or greater
+    /// sequence number, or `seq` is after the most recent value, this is
+    /// rejected.
     Compact { seq: SequenceNumber },
 }
 
@@ -73,10 +93,33 @@ pub enum MessageToDatabase {
         key: Key,
 
         /// Value to push.
-        value: Value,
+        value: Arc<Value>,
 
         /// Describes the action that this should have on the state.
         action: Action,
+
+        /// Time-to-live of the pushed value, in milliseconds. Overrides the
+        /// default TTL of the key. Relayed values are never stored, so this
+        /// has no effect on them.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<u64>,
+    },
+    /// Apply several pushes at once. They receive contiguous sequence numbers,
+    /// and subscribers receive them together.
+    Batch {
+        ops: Vec<PushOp>,
+
+        /// If set, the batch is rejected as a whole if the precondition of any
+        /// push fails. Each precondition is checked against the state left by
+        /// the pushes before it.
+        #[serde(default)]
+        atomic: bool,
+    },
+    /// Set the default time-to-live, in milliseconds, of values later pushed
+    /// to a key. A TTL of `None` removes the default.
+    SetTtl {
+        key: Key,
+        ttl: Option<u64>,
     },
     Get {
         /// Key to get.
@@ -84,42 +127,268 @@ pub enum MessageToDatabase {
         /// Sequence number to start from.
         #[serde(default = "default_seq")]
         seq: Option<SequenceNumber>,
+        /// Only return values with a sequence number up to and including this.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        until_seq: Option<SequenceNumber>,
+        /// Maximum number of values to return. If more remain, the `Init`
+        /// message includes a cursor to continue from.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+        /// Return the most recent values first.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        reverse: bool,
+        /// If set, and more than this many values are returned, they are split
+        /// into `InitChunk` messages of at most this many values each, instead
+        /// of a single `Init` message. Ignored for prefix gets.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        chunk_size: Option<usize>,
+        /// If set, `key` is treated as a prefix: the connection subscribes to
+        /// every key which starts with it, including keys created later, and
+        /// receives a `Batch` of `Init` messages, one per existing matching key.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        prefix: bool,
+        /// Whether to receive later pushes. If not set, only the current
+        /// data is returned.
+        #[serde(default = "default_subscribe")]
+        subscribe: bool,
+        /// If set, and the connection subscribes, it volunteers to compact the
+        /// key, or every key which starts with it for a prefix get. One
+        /// volunteer is elected per key, and is sent `CompactionRequested`
+        /// whenever the stream grows past this many values.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compact_threshold: Option<usize>,
+    },
+    /// List the keys which have a stream, in key order.
+    ListKeys {
+        /// Only list keys which start with this prefix.
+        #[serde(default)]
+        prefix: String,
+        /// Only list keys after this one, as returned in `next_cursor`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<Key>,
+        /// Maximum number of keys to return.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        limit: Option<usize>,
+    },
+    /// Stop receiving pushes for a key, or for a prefix subscription if
+    /// `prefix` is set.
+    Unsubscribe {
+        key: Key,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        prefix: bool,
     },
     Ping {
         nonce: Option<u64>,
     },
 }
 
+/// A message from a client, with an optional id that is echoed back in any
+/// `Error` the message causes.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct Request {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    #[serde(flatten)]
+    pub message: MessageToDatabase,
+}
+
+impl Request {
+    /// The same request, with its message sent without subscribing. See
+    /// [`MessageToDatabase::without_subscription`].
+    pub fn without_subscription(self) -> Self {
+        Request {
+            request_id: self.request_id,
+            message: self.message.without_subscription(),
+        }
+    }
+}
+
+/// A single push within a `Batch` message.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct PushOp {
+    pub key: Key,
+    pub value: Arc<Value>,
+    pub action: Action,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ttl: Option<u64>,
+}
+
+impl MessageToDatabase {
+    /// The same message, but without subscribing the connection it is sent
+    /// on, and without splitting the response into chunks. Used for one-off
+    /// requests, such as those made over HTTP, which only return one response.
+    pub fn without_subscription(mut self) -> Self {
+        if let MessageToDatabase::Get {
+            subscribe,
+            chunk_size,
+            ..
+        } = &mut self
+        {
+            *subscribe = false;
+            *chunk_size = None;
+        }
+        self
+    }
+}
+
 fn default_seq() -> Option<SequenceNumber> {
     Some(SequenceNumber(0))
 }
 
+fn default_subscribe() -> bool {
+    true
+}
+
+/// A value and its sequence number. Values are immutable once pushed, and
+/// shared between the store and every message which contains them.
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 pub struct SequenceValue {
-    pub value: Value,
+    pub value: Arc<Value>,
     pub seq: SequenceNumber,
 }
 
+/// A key listed in response to `ListKeys`.
+#[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
+pub struct KeyInfo {
+    pub key: Key,
+    /// Number of values retained for the key.
+    pub size: usize,
+    /// Sequence number of the most recent value retained for the key.
+    pub latest_seq: SequenceNumber,
+}
+
+/// The kind of an [`Error`](crate::Error), as sent to clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    DatabaseGone,
+    InvalidKey,
+    QuotaExceeded,
+    DecodeFailed,
+    Conflict,
+    Unauthorized,
+    StorageFailed,
+}
+
 #[derive(Debug, PartialEq, Clone, Deserialize, Serialize)]
 #[serde(tag = "type", rename_all = "snake_case")]
 pub enum MessageFromDatabase {
     Push {
         key: Key,
-        value: Value,
+        value: Arc<Value>,
         seq: SequenceNumber,
+        /// Whether the value was relayed without being stored, so that it can
+        /// not be recovered with a `Get` if it is missed.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        relay: bool,
     },
     Init {
         key: Key,
         data: Vec<SequenceValue>,
+        /// The sequence number the stream has been compacted through, if any.
+        /// Values up to it are replaced by a single value with this sequence
+        /// number, so the replay begins there.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compacted_through: Option<SequenceNumber>,
+        /// If the `Get` was limited and more values remain, the sequence number
+        /// to pass as `seq` (or as `until_seq`, when reading in reverse) to
+        /// continue reading.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<SequenceNumber>,
+    },
+    /// Part of the response to a `Get` which was split into chunks. Chunks
+    /// are sent in order, and no other message for the key is sent between
+    /// them.
+    InitChunk {
+        key: Key,
+        data: Vec<SequenceValue>,
+        /// Set on the first chunk, as in `Init`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        compacted_through: Option<SequenceNumber>,
+        /// Whether this is the final chunk.
+        last: bool,
+        /// Set on the final chunk, as in `Init`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        cursor: Option<SequenceNumber>,
     },
     Error {
+        /// A stable, machine-readable code for the error.
+        code: ErrorCode,
+        /// A human-readable description of the error.
         message: String,
+        /// The `request_id` of the message which caused the error, if it had one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
     },
     StreamSize {
         key: Key,
         size: usize,
     },
+    /// The stream of a key was deleted.
+    Deleted {
+        key: Key,
+        seq: SequenceNumber,
+    },
+    /// The stream of a key was compacted through `seq`. Values up to and
+    /// including `seq` were replaced by a single value with that sequence
+    /// number, so a client holding an older view of the stream should `Get`
+    /// it again.
+    Compacted {
+        key: Key,
+        seq: SequenceNumber,
+    },
+    /// Sent to the one subscriber of a key elected to compact it, when the
+    /// stream of the key grows past the subscriber's compaction threshold, or
+    /// when it is newly elected and the stream is already past it.
+    CompactionRequested {
+        key: Key,
+        size: usize,
+    },
+    /// Messages for these keys were dropped because the connection could not
+    /// keep up. The client should `Get` them again.
+    ResyncRequired {
+        keys: Vec<Key>,
+    },
+    /// A `ReplaceIf` push was rejected because the stream had changed.
+    Conflict {
+        key: Key,
+        expected_seq: SequenceNumber,
+        /// The most recent value retained for the key, if any.
+        current: Option<SequenceValue>,
+        /// The `request_id` of the push which was rejected, if it had one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        request_id: Option<String>,
+    },
     Pong {
         nonce: Option<u64>,
     },
+    /// Response to `ListKeys`.
+    Keys {
+        keys: Vec<KeyInfo>,
+        /// If there are more keys to list, the cursor to pass to the next
+        /// `ListKeys` message.
+        next_cursor: Option<Key>,
+    },
+    /// Several messages which result from a single batch, to be processed
+    /// together.
+    Batch {
+        messages: Vec<MessageFromDatabase>,
+    },
+}
+
+impl MessageFromDatabase {
+    /// Set the `request_id` of every `Error` and `Conflict` message within
+    /// this one, as the response to the request with that id.
+    pub(crate) fn set_request_id(&mut self, id: &str) {
+        match self {
+            MessageFromDatabase::Error { request_id, .. }
+            | MessageFromDatabase::Conflict { request_id, .. } => {
+                *request_id = Some(id.to_string())
+            }
+            MessageFromDatabase::Batch { messages } => {
+                messages.iter_mut().for_each(|m| m.set_request_id(id))
+            }
+            _ => {}
+        }
+    }
 }
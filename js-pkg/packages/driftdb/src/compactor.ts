@@ -39,7 +39,7 @@ export class Compactor<T, A> {
 
     compactable: Compactable<T, A>
 
-    /** The number of messages to keep in the stream before compacting. */
+    /** The number of messages to keep in the stream before the server asks for it to be compacted. */
     sizeThreshold?: number
 
     /** The database connection to use. */
@@ -61,7 +61,7 @@ export class Compactor<T, A> {
     this.sizeThreshold = opts.sizeThreshold || 30
 
     this.onSequenceValue = this.onSequenceValue.bind(this)
-    this.onSize = this.onSize.bind(this)
+    this.onCompactionRequested = this.onCompactionRequested.bind(this)
     this.dispatch = this.dispatch.bind(this)
   }
 
@@ -75,11 +75,15 @@ export class Compactor<T, A> {
 
   subscribe() {
     this.lastConfirmedState = this.maybeClone(this.state)
-    this.db.subscribe(this.key, this.onSequenceValue, this.onSize)
+    this.db.onCompactionRequested(this.key, this.onCompactionRequested)
+    this.db.subscribe(this.key, this.onSequenceValue, undefined, {
+      compactThreshold: this.sizeThreshold
+    })
   }
 
   destroy() {
-    this.db.unsubscribe(this.key, this.onSequenceValue, this.onSize)
+    this.db.unsubscribe(this.key, this.onSequenceValue)
+    this.db.offCompactionRequested(this.key, this.onCompactionRequested)
   }
 
   dispatch(action: A) {
@@ -128,8 +132,11 @@ export class Compactor<T, A> {
     console.log('Unknown message', sequenceValue.value)
   }
 
-  onSize(size: number) {
-    if (size > this.sizeThreshold && this.lastConfirmedSeq !== 0) {
+  /// Only the subscriber elected as the compactor is asked to compact, and only
+  /// once the stream has grown past its threshold, so that clients do not race
+  /// each other to compact the same stream.
+  onCompactionRequested(_size: number) {
+    if (this.lastConfirmedSeq !== 0) {
       const newState = this.compactable.packState(this.lastConfirmedState!)
       this.db?.send({
         type: 'push',
@@ -14,9 +14,19 @@ export type { DataChannelMsg } from './webrtc'
 
 const CLIENT_ID_KEY = '_driftdb_client_id'
 
+/** Maximum number of values the server should send in a single message when replaying a stream. */
+const INIT_CHUNK_SIZE = 1000
+
 export interface SubscribeOptions {
   /** Whether to replay history when subscribing. */
   replay?: boolean
+  /** The maximum number of values in each message of the replay. */
+  chunkSize?: number
+  /**
+   * If set, volunteer to compact the key. The server elects one volunteer per key, and sends it a
+   * `compaction_requested` message whenever the stream grows past this many values.
+   */
+  compactThreshold?: number
 }
 
 export type DbConnectionParams = {
@@ -34,6 +44,7 @@ export class DbConnection {
   messageListener = new EventListener<MessageFromDb>()
   subscriptions = new SubscriptionManager<SequenceValue>()
   sizeSubscriptions = new SubscriptionManager<number>()
+  compactionSubscriptions = new SubscriptionManager<number>()
   queue: Array<MessageToDb> = []
   dbUrl: string | null = null
   reconnectLoopHandle: ReturnType<typeof setTimeout> | null = null
@@ -127,41 +138,59 @@ export class DbConnection {
         message = JSON.parse(event.data)
       }
 
-      this.messageListener.dispatch(message)
-
-      switch (message.type) {
-        case 'init':
-          let key = message.key
-          message.data.forEach((value) => {
-            this.subscriptions.dispatch(key, value)
-          })
-          break
-        case 'push':
-          this.subscriptions.dispatch(message.key, {
-            seq: message.seq,
-            value: message.value
-          })
-          break
-        case 'stream_size':
-          this.sizeSubscriptions.dispatch(message.key, message.size)
-          break
-        case 'pong':
-          if (this.activeLatencyTest) {
-            this.activeLatencyTest.receivedResponse()
-            this.activeLatencyTest = null
-          }
-          break
-        case 'error':
-          console.error('Error from server:', message)
-          break
-        default:
-          console.error('Unknown message type', (message as MessageFromDb).type)
-      }
+      this.handleMessage(message)
     }
 
     return promise
   }
 
+  private handleMessage(message: MessageFromDb) {
+    this.messageListener.dispatch(message)
+
+    switch (message.type) {
+      case 'init':
+      case 'init_chunk':
+        let key = message.key
+        message.data.forEach((value) => {
+          this.subscriptions.dispatch(key, value)
+        })
+        break
+      case 'push':
+        this.subscriptions.dispatch(message.key, {
+          seq: message.seq,
+          value: message.value
+        })
+        break
+      case 'stream_size':
+        this.sizeSubscriptions.dispatch(message.key, message.size)
+        break
+      case 'compaction_requested':
+        this.compactionSubscriptions.dispatch(message.key, message.size)
+        break
+      case 'pong':
+        if (this.activeLatencyTest) {
+          this.activeLatencyTest.receivedResponse()
+          this.activeLatencyTest = null
+        }
+        break
+      case 'deleted':
+      case 'compacted':
+      case 'conflict':
+      case 'keys':
+      case 'resync_required':
+        // Tombstones, compactions, resyncs and responses to specific requests are left to message listeners.
+        break
+      case 'batch':
+        message.messages.forEach((message) => this.handleMessage(message))
+        break
+      case 'error':
+        console.error('Error from server:', message)
+        break
+      default:
+        console.error('Unknown message type', (message as MessageFromDb).type)
+    }
+  }
+
   /**
    * Test the connection latency by sending a ping to the server.
    *
@@ -206,6 +235,7 @@ export class DbConnection {
     }
     this.subscriptions = new SubscriptionManager()
     this.sizeSubscriptions = new SubscriptionManager()
+    this.compactionSubscriptions = new SubscriptionManager()
   }
 
   private setStatus(connected: boolean) {
@@ -255,8 +285,17 @@ export class DbConnection {
       this.sizeSubscriptions.subscribe(key, sizeCallback)
     }
     let replay = subscribeOptions?.replay ?? true
-    let seq = replay ? 0 : null
-    this.send({ type: 'get', key, seq })
+    // Left out unless set, since the binary encoding has no equivalent of `undefined`.
+    let compactor =
+      subscribeOptions?.compactThreshold !== undefined
+        ? { compact_threshold: subscribeOptions.compactThreshold }
+        : {}
+    if (replay) {
+      let chunk_size = subscribeOptions?.chunkSize ?? INIT_CHUNK_SIZE
+      this.send({ type: 'get', key, seq: 0, chunk_size, ...compactor })
+    } else {
+      this.send({ type: 'get', key, seq: null, ...compactor })
+    }
   }
 
   /**
@@ -271,11 +310,34 @@ export class DbConnection {
     listener: (event: SequenceValue) => void,
     sizeCallback?: (size: number) => void
   ) {
-    this.subscriptions.unsubscribe(subject, listener)
+    if (this.subscriptions.unsubscribe(subject, listener)) {
+      this.send({ type: 'unsubscribe', key: subject })
+    }
     if (sizeCallback) {
       this.sizeSubscriptions.unsubscribe(subject, sizeCallback)
     }
   }
+
+  /**
+   * Listen for requests to compact a key, which the server sends only to the one subscriber it has elected as the key's compactor,
+   * out of those which subscribed with a `compactThreshold`.
+   *
+   * @param key The key, which should also be subscribed to.
+   * @param callback A callback that will be called with the size of the server's retained stream whenever it should be compacted.
+   */
+  onCompactionRequested(key: Key, callback: (size: number) => void) {
+    this.compactionSubscriptions.subscribe(key, callback)
+  }
+
+  /**
+   * Stop listening for requests to compact a key.
+   *
+   * @param key The key.
+   * @param callback The callback that was passed to `onCompactionRequested`.
+   */
+  offCompactionRequested(key: Key, callback: (size: number) => void) {
+    this.compactionSubscriptions.unsubscribe(key, callback)
+  }
 }
 
 /**
@@ -324,14 +386,24 @@ class SubscriptionManager<T> {
     subscription.addListener(listener)
   }
 
-  unsubscribe(subject: Key, listener: (event: T) => void) {
-    const key = JSON.stringify(subject)
+  /**
+   * Remove a listener for a key.
+   *
+   * @returns Whether no listeners remain for the key.
+   */
+  unsubscribe(key: Key, listener: (event: T) => void): boolean {
     if (!this.subscriptions.has(key)) {
-      return
+      return true
     }
 
     const subscription = this.subscriptions.get(key)!
     subscription.removeListener(listener)
+
+    if (subscription.listeners.length === 0) {
+      this.subscriptions.delete(key)
+      return true
+    }
+    return false
   }
 
   dispatch(key: Key, event: T) {
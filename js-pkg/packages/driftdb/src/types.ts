@@ -2,7 +2,9 @@ export type Key = string
 export type SequenceNumber = number
 
 export type Action =
-  | { type: 'append' | 'replace' | 'relay' }
+  | { type: 'append' | 'replace' | 'relay' | 'delete' }
+  | { type: 'append_capped'; max_len: number }
+  | { type: 'replace_if'; expected_seq: SequenceNumber }
   | { type: 'compact'; seq: SequenceNumber }
 
 export interface SequenceValue {
@@ -10,48 +12,149 @@ export interface SequenceValue {
   seq: SequenceNumber
 }
 
+export interface PushOp {
+  key: Key
+  value: unknown
+  action: Action
+  ttl?: number
+}
+
+export type ErrorCode =
+  | 'database_gone'
+  | 'invalid_key'
+  | 'quota_exceeded'
+  | 'decode_failed'
+  | 'conflict'
+  | 'unauthorized'
+  | 'storage_failed'
+
+export interface KeyInfo {
+  key: Key
+  size: number
+  latest_seq: SequenceNumber
+}
+
 export type MessageFromDb =
   | {
       type: 'push'
       key: Key
       value: unknown
       seq: SequenceNumber
+      relay?: boolean
     }
   | {
       type: 'init'
       data: Array<SequenceValue>
       key: Key
+      cursor?: SequenceNumber
+      compacted_through?: SequenceNumber
+    }
+  | {
+      type: 'init_chunk'
+      data: Array<SequenceValue>
+      key: Key
+      last: boolean
+      cursor?: SequenceNumber
+      compacted_through?: SequenceNumber
     }
   | {
       type: 'error'
+      code: ErrorCode
       message: string
+      request_id?: string
     }
   | {
       type: 'stream_size'
       key: Key
       size: number
     }
+  | {
+      type: 'deleted'
+      key: Key
+      seq: SequenceNumber
+    }
+  | {
+      type: 'compacted'
+      key: Key
+      seq: SequenceNumber
+    }
+  | {
+      type: 'compaction_requested'
+      key: Key
+      size: number
+    }
+  | {
+      type: 'conflict'
+      key: Key
+      expected_seq: SequenceNumber
+      current: SequenceValue | null
+      request_id?: string
+    }
+  | {
+      type: 'resync_required'
+      keys: Array<Key>
+    }
   | {
       type: 'pong'
       nonce?: number
     }
+  | {
+      type: 'keys'
+      keys: Array<KeyInfo>
+      next_cursor: Key | null
+    }
+  | {
+      type: 'batch'
+      messages: Array<MessageFromDb>
+    }
 
-export type MessageToDb =
+/** Any message to the database may carry a `request_id`, which is echoed back in any error or conflict it causes. */
+export type MessageToDb = (
   | {
       type: 'push'
       action: Action
       value: unknown
       key: Key
+      ttl?: number
+    }
+  | {
+      type: 'batch'
+      ops: Array<PushOp>
+      atomic?: boolean
+    }
+  | {
+      type: 'set_ttl'
+      key: Key
+      ttl: number | null
     }
   | {
       type: 'get'
       key: Key
       seq?: SequenceNumber | null
+      until_seq?: SequenceNumber
+      limit?: number
+      reverse?: boolean
+      chunk_size?: number
+      prefix?: boolean
+      subscribe?: boolean
+      compact_threshold?: number
+    }
+  | {
+      type: 'list_keys'
+      prefix?: string
+      cursor?: Key
+      limit?: number
+    }
+  | {
+      type: 'unsubscribe'
+      key: Key
+      prefix?: boolean
     }
   | {
       type: 'ping'
       nonce?: number
     }
+) & { request_id?: string }
 
 export type ConnectionStatus =
   | {